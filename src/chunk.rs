@@ -1,10 +1,38 @@
-use crate::value::ValueType;
+use std::collections::HashMap;
+
+use crate::{heap::ObjRef, value::ValueType};
+
+/// Largest constant index the 24-bit operand of the long instructions can address
+pub const MAX_CONSTANTS: usize = 1 << 24;
 
 #[derive(Debug, Clone)]
 pub struct Chunk {
     op_codes: Vec<u8>,
     lines: Vec<usize>,
     pub constants: Vec<ValueType>,
+    /// Index of the constants that can be shared, so each distinct literal or name is stored once
+    constant_idx: HashMap<ConstantKey, usize>,
+}
+
+/// Numbers are keyed by their bits so `0` and `-0` stay distinct constants
+/// Objects are keyed by handle, strings are interned so equal strings share one
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum ConstantKey {
+    Bool(bool),
+    Nil,
+    Number(u64),
+    Obj(ObjRef),
+}
+
+impl ConstantKey {
+    fn from_value(value: &ValueType) -> Self {
+        match value {
+            ValueType::Bool(b) => ConstantKey::Bool(*b),
+            ValueType::Nil => ConstantKey::Nil,
+            ValueType::Number(n) => ConstantKey::Number(n.to_bits()),
+            ValueType::Obj(obj) => ConstantKey::Obj(*obj),
+        }
+    }
 }
 
 impl Default for Chunk {
@@ -19,6 +47,7 @@ impl Chunk {
             op_codes: Vec::new(),
             lines: Vec::new(),
             constants: Vec::new(),
+            constant_idx: HashMap::new(),
         }
     }
 
@@ -48,7 +77,7 @@ impl Chunk {
 
     pub fn get_constant(&self, idx: usize) -> ValueType {
         if idx < self.constants.len() {
-            return self.constants[idx].clone();
+            return self.constants[idx];
         }
         panic!("[Chunk-Constant] Offset is higher than line count");
     }
@@ -58,11 +87,18 @@ impl Chunk {
         self.lines.push(line);
     }
 
-    /// Add the given ValueType to constants
+    /// Add the given ValueType to constants, reusing the existing entry for an equal literal
     /// Return the index of the constant
     pub fn add_constant(&mut self, value: ValueType) -> usize {
+        let key = ConstantKey::from_value(&value);
+        if let Some(idx) = self.constant_idx.get(&key) {
+            return *idx;
+        }
+
         self.constants.push(value);
+        let idx = self.constants.len() - 1;
+        self.constant_idx.insert(key, idx);
 
-        self.constants.len() - 1
+        idx
     }
 }
@@ -1,82 +1,114 @@
 use crate::{
-    chunk::Chunk,
+    chunk::{Chunk, MAX_CONSTANTS},
     debug::disassemble_chunk,
-    object::{ObjString, Object},
+    diagnostic::Diagnostic,
+    heap::{Heap, ObjRef},
+    object::{ObjFunction, Object},
     opcode::OpCode,
     scanner::Scanner,
     token::{Token, TokenType},
-    value::ValueType,
+    value::{is_exact_integer, ValueType},
 };
 use std::default::Default;
 
-pub struct Compiler<'scanner, 'chunk> {
+pub struct Compiler<'scanner, 'heap> {
     scanner: Scanner<'scanner>,
+    /// Strings and functions in constants live on the VM's heap, like the ones created at runtime
+    /// Nothing is collected while compiling, the VM only collects once the script is running
+    heap: &'heap mut Heap,
     current: Option<Token>,
     previous: Option<Token>,
-    chunk: &'chunk mut Chunk,
-    local_track: LocalTracking,
-    had_error: bool,
+    states: Vec<FunctionState>,
+    /// Class declarations enclosing the code being compiled, innermost at the top
+    classes: Vec<ClassState>,
+    diagnostics: Vec<Diagnostic>,
+    /// Lines of the `///` comments in front of the current token
+    doc_comment: Vec<String>,
     panic_mode: bool,
     debug: bool,
+    repl: bool,
 }
 
-impl<'scanner, 'chunk> Compiler<'scanner, 'chunk> {
-    pub fn new(source: &'scanner str, chunk: &'chunk mut Chunk) -> Self {
+impl<'scanner, 'heap> Compiler<'scanner, 'heap> {
+    pub fn new(source: &'scanner str, heap: &'heap mut Heap) -> Self {
         Compiler {
             scanner: Scanner::new(source),
+            heap,
             current: None,
             previous: None,
-            had_error: false,
+            diagnostics: Vec::new(),
+            doc_comment: Vec::new(),
             panic_mode: false,
             debug: false,
-            local_track: LocalTracking::default(),
-            chunk,
+            repl: false,
+            states: vec![FunctionState::new(FunctionType::Script, None)],
+            classes: Vec::new(),
         }
     }
 
+    pub fn set_repl(&mut self, state: bool) {
+        self.repl = state
+    }
+
     fn synchronize(&mut self) {
         self.panic_mode = false;
 
-        loop {
-            if let Some(curr_token) = self.current.take() {
-                if curr_token.token_type != TokenType::EOF {
-                    if let Some(prev_token) = self.previous.as_ref() {
-                        if prev_token.token_type == TokenType::SEMICOLON {
-                            return;
-                        }
-                    }
+        while !self.check_token(TokenType::EOF) {
+            if let Some(prev_token) = self.previous.as_ref() {
+                if prev_token.token_type == TokenType::SEMICOLON {
+                    return;
+                }
+            }
 
-                    match curr_token.token_type {
-                        TokenType::CLASS
-                        | TokenType::FUN
-                        | TokenType::VAR
-                        | TokenType::FOR
-                        | TokenType::IF
-                        | TokenType::WHILE
-                        | TokenType::PRINT
-                        | TokenType::RETURN => break,
-                        _ => (),
-                    }
+            if let Some(curr_token) = self.current.as_ref() {
+                match curr_token.token_type {
+                    TokenType::CLASS
+                    | TokenType::FUN
+                    | TokenType::VAR
+                    | TokenType::CONST
+                    | TokenType::FOR
+                    | TokenType::IF
+                    | TokenType::WHILE
+                    | TokenType::PRINT
+                    | TokenType::RETURN => return,
+                    _ => (),
                 }
             }
             self.advance();
         }
     }
 
-    pub fn compile(&mut self) -> bool {
+    /// Compiles the whole source as the implicit top-level function
+    /// Returns every diagnostic collected if there is any
+    pub fn compile(&mut self) -> Result<ObjRef, Vec<Diagnostic>> {
         self.advance();
 
         while !self.match_token(TokenType::EOF) {
             self.declaration()
         }
-        self.end_compiler();
+        let (function, _) = self.end_compiler();
+
+        match self.had_error() {
+            true => Err(std::mem::take(&mut self.diagnostics)),
+            false => Ok(function),
+        }
+    }
 
-        !self.had_error
+    fn had_error(&self) -> bool {
+        !self.diagnostics.is_empty()
     }
 
     fn declaration(&mut self) {
-        if self.match_token(TokenType::VAR) {
+        // Doc comments only stick to the declaration right after them
+        let doc = self.take_doc_comment();
+        if self.match_token(TokenType::CLASS) {
+            self.class_declaration(doc);
+        } else if self.match_token(TokenType::FUN) {
+            self.fun_declaration(doc);
+        } else if self.match_token(TokenType::VAR) {
             self.var_declaration();
+        } else if self.match_token(TokenType::CONST) {
+            self.const_declaration();
         } else {
             self.statement();
         }
@@ -86,6 +118,210 @@ impl<'scanner, 'chunk> Compiler<'scanner, 'chunk> {
         }
     }
 
+    /// Emits the class, binds it to its name and then adds the methods one by one
+    /// The class is loaded back on the stack while the methods are compiled so `METHOD` can find it
+    fn class_declaration(&mut self, doc: Option<ObjRef>) {
+        self.consume(TokenType::IDENTIFIER, "Expect class name.");
+        let class_name = match self.previous.clone() {
+            Some(token) => token,
+            None => return,
+        };
+        let name_constant = self.identifier_constant(class_name.clone());
+        self.declare_variable();
+
+        self.emit_operand_op(OpCode::CLASS, name_constant);
+        if let Some(doc) = doc {
+            let doc_constant = self.make_constant(ValueType::Obj(doc));
+            self.emit_operand_op(OpCode::ClassDoc, doc_constant);
+        }
+        self.define_variable(name_constant);
+
+        self.classes.push(ClassState {
+            has_superclass: false,
+        });
+
+        if self.match_token(TokenType::LESS) {
+            self.consume(TokenType::IDENTIFIER, "Expect superclass name.");
+            self.variable(false);
+
+            if let Some(superclass_name) = self.previous.as_ref() {
+                if class_name.is_equal(superclass_name) {
+                    self.error("A class can't inherit from itself.");
+                }
+            }
+
+            // Each subclass gets its own scope holding `super`, so methods capture the right superclass
+            self.begin_scope();
+            self.add_local(Self::synthetic_token(TokenType::SUPER, "super"));
+            self.define_variable(0);
+
+            self.named_variable(class_name.clone(), false);
+            self.emit_byte(OpCode::INHERIT as u8);
+            if let Some(class_state) = self.classes.last_mut() {
+                class_state.has_superclass = true;
+            }
+        }
+
+        self.named_variable(class_name, false);
+        self.consume(TokenType::LEFTBRACE, "Expect '{' before class body.");
+        while !self.check_token(TokenType::RIGHTBRACE) && !self.check_token(TokenType::EOF) {
+            self.method();
+        }
+        self.consume(TokenType::RIGHTBRACE, "Expect '}' after class body.");
+        self.emit_byte(OpCode::POP as u8);
+
+        if let Some(class_state) = self.classes.pop() {
+            if class_state.has_superclass {
+                self.end_scope();
+            }
+        }
+    }
+
+    fn method(&mut self) {
+        let doc = self.take_doc_comment();
+        self.consume(TokenType::IDENTIFIER, "Expect method name.");
+        let method_name = match self.previous.clone() {
+            Some(token) => token,
+            None => return,
+        };
+
+        let function_type = match method_name.lexeme.as_str() {
+            "init" => FunctionType::Initializer,
+            _ => FunctionType::Method,
+        };
+        let constant = self.identifier_constant(method_name);
+        self.function(function_type, doc);
+        self.emit_operand_op(OpCode::METHOD, constant);
+    }
+
+    /// A function declaration binds the function object to a variable like `var` does.
+    /// The name is marked initialized before compiling the body so the function can refer to itself.
+    fn fun_declaration(&mut self, doc: Option<ObjRef>) {
+        let global = self.parse_variable("Expect function name.");
+        self.mark_initialized();
+        self.function(FunctionType::Function, doc);
+        self.define_variable(global);
+    }
+
+    /// Compiles the parameters and body into a new ObjFunction with its own chunk
+    fn function(&mut self, function_type: FunctionType, doc: Option<ObjRef>) {
+        let name = match self.previous.as_ref() {
+            Some(token) => Some(self.heap.intern(&token.lexeme)),
+            None => None,
+        };
+        self.begin_function(function_type, name, doc);
+
+        self.consume(TokenType::LEFTPAREN, "Expect '(' after function name.");
+        self.parameters();
+        self.consume(TokenType::LEFTBRACE, "Expect '{' before function body.");
+        self.block();
+        self.end_function();
+    }
+
+    /// `fun (a, b) { ... }` in an expression, compiled like a declaration without a name
+    fn lambda(&mut self, _can_assign: bool) {
+        let name = self.heap.intern("anonymous");
+        self.begin_function(FunctionType::Function, Some(name), None);
+
+        self.consume(TokenType::LEFTPAREN, "Expect '(' after 'fun'.");
+        self.parameters();
+        self.consume(TokenType::LEFTBRACE, "Expect '{' before function body.");
+        self.block();
+        self.end_function();
+    }
+
+    /// `(a, b) => a + b`, called after the '(' was consumed
+    /// The body is a single expression whose value is returned
+    fn arrow_function(&mut self) {
+        let name = self.heap.intern("anonymous");
+        self.begin_function(FunctionType::Function, Some(name), None);
+
+        self.parameters();
+        self.consume(TokenType::ARROW, "Expect '=>' after parameters.");
+        self.expression();
+        self.emit_byte(OpCode::RETURN as u8);
+        self.end_function();
+    }
+
+    /// Tells whether the '(' just consumed starts the parameters of an arrow function
+    /// rather than a grouping, by scanning ahead for `ident, ...) =>` on a copy of the scanner
+    fn is_arrow_function(&self) -> bool {
+        let mut token_type = match self.current.as_ref() {
+            Some(token) => token.token_type,
+            None => return false,
+        };
+        let mut scanner = self.scanner.clone();
+        let mut next = || loop {
+            let token_type = scanner.scan_token().token_type;
+            if token_type != TokenType::DOCCOMMENT {
+                return token_type;
+            }
+        };
+
+        if token_type != TokenType::RIGHTPAREN {
+            loop {
+                if token_type != TokenType::IDENTIFIER {
+                    return false;
+                }
+                token_type = next();
+                if token_type != TokenType::COMMA {
+                    break;
+                }
+                token_type = next();
+            }
+            if token_type != TokenType::RIGHTPAREN {
+                return false;
+            }
+        }
+
+        next() == TokenType::ARROW
+    }
+
+    fn begin_function(
+        &mut self,
+        function_type: FunctionType,
+        name: Option<ObjRef>,
+        doc: Option<ObjRef>,
+    ) {
+        self.states.push(FunctionState::new(function_type, name));
+        self.state().function.doc = doc;
+        self.begin_scope();
+    }
+
+    /// Parameters are just locals declared in the function's outermost scope
+    /// Called after the '(', consumes the closing ')'
+    fn parameters(&mut self) {
+        if !self.check_token(TokenType::RIGHTPAREN) {
+            loop {
+                if self.state().function.arity == u8::MAX {
+                    self.error_at_current("Can't have more than 255 parameters.");
+                } else {
+                    self.state().function.arity += 1;
+                }
+
+                let constant = self.parse_variable("Expect parameter name.");
+                self.define_variable(constant);
+
+                if !self.match_token(TokenType::COMMA) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::RIGHTPAREN, "Expect ')' after parameters.");
+    }
+
+    /// Finishes the function being compiled and emits the closure creating it at runtime
+    fn end_function(&mut self) {
+        // No end_scope here, the frame's slots are discarded by the VM on return
+        let (function, upvalues) = self.end_compiler();
+        let constant = self.make_constant(ValueType::Obj(function));
+        self.emit_operand_op(OpCode::CLOSURE, constant);
+
+        for upvalue in upvalues {
+            self.emit_bytes(upvalue.is_local as u8, upvalue.index);
+        }
+    }
+
     /// Parse the Variable and get the index of constant
     /// If the current token is = then next expression value to constant else NIL
     fn var_declaration(&mut self) {
@@ -105,6 +341,32 @@ impl<'scanner, 'chunk> Compiler<'scanner, 'chunk> {
         self.define_variable(global);
     }
 
+    /// Like `var` but the value is required and the variable can't be assigned afterwards
+    /// Assigning a local constant is caught here, a global one only when the script runs
+    fn const_declaration(&mut self) {
+        let global = self.parse_variable("Expect constant name.");
+        if *self.local_track().depth() > 0 {
+            let local_track = self.local_track();
+            if let Some(local) = local_track.locals[(local_track.local_count - 1) as usize].as_mut()
+            {
+                local.is_const = true;
+            }
+        }
+
+        self.consume(TokenType::EQUAL, "Expect '=' after constant name.");
+        self.expression();
+        self.consume(
+            TokenType::SEMICOLON,
+            "Expect ';' after constant declaration.",
+        );
+
+        if *self.local_track().depth() > 0 {
+            self.mark_initialized();
+            return;
+        }
+        self.emit_operand_op(OpCode::DefineConstGlobal, global);
+    }
+
     fn statement(&mut self) {
         if self.match_token(TokenType::PRINT) {
             self.print_statement();
@@ -114,6 +376,12 @@ impl<'scanner, 'chunk> Compiler<'scanner, 'chunk> {
             self.while_statement();
         } else if self.match_token(TokenType::FOR) {
             self.for_statement();
+        } else if self.match_token(TokenType::RETURN) {
+            self.return_statement();
+        } else if self.match_token(TokenType::BREAK) {
+            self.break_statement();
+        } else if self.match_token(TokenType::CONTINUE) {
+            self.continue_statement();
         } else if self.match_token(TokenType::LEFTBRACE) {
             self.begin_scope();
             self.block();
@@ -137,7 +405,7 @@ impl<'scanner, 'chunk> Compiler<'scanner, 'chunk> {
             self.expression_statement();
         }
 
-        let mut loop_start = self.chunk.op_codes_len();
+        let mut loop_start = self.current_chunk().op_codes_len();
         let mut exit_jump = None;
         if !self.match_token(TokenType::SEMICOLON) {
             self.expression();
@@ -149,7 +417,7 @@ impl<'scanner, 'chunk> Compiler<'scanner, 'chunk> {
 
         if !self.match_token(TokenType::RIGHTPAREN) {
             let body_jump = self.emit_jump(OpCode::JUMP as u8);
-            let increament_start = self.chunk.op_codes_len();
+            let increament_start = self.current_chunk().op_codes_len();
             self.expression();
             self.emit_byte(OpCode::POP as u8);
 
@@ -160,6 +428,8 @@ impl<'scanner, 'chunk> Compiler<'scanner, 'chunk> {
             self.patch_jump(body_jump);
         }
 
+        // `continue` runs the increment, the loop variable itself is only popped by end_scope
+        self.begin_loop(loop_start);
         self.statement();
         self.emit_loop(loop_start);
 
@@ -168,22 +438,99 @@ impl<'scanner, 'chunk> Compiler<'scanner, 'chunk> {
             self.emit_byte(OpCode::POP as u8);
         }
 
+        self.end_loop();
         self.end_scope();
     }
 
+    /// Leaves the innermost loop, discarding the locals declared inside its body
+    fn break_statement(&mut self) {
+        match self.state().loops.last() {
+            Some(loop_state) => {
+                let scope_depth = loop_state.scope_depth;
+                self.consume(TokenType::SEMICOLON, "Expect ';' after 'break'.");
+                self.discard_locals(scope_depth);
+
+                let jump = self.emit_jump(OpCode::JUMP as u8);
+                if let Some(loop_state) = self.state().loops.last_mut() {
+                    loop_state.break_jumps.push(jump);
+                }
+            }
+            None => {
+                self.error("Can't use 'break' outside of a loop.");
+                self.consume(TokenType::SEMICOLON, "Expect ';' after 'break'.");
+            }
+        }
+    }
+
+    /// Jumps back to the start of the next iteration of the innermost loop
+    fn continue_statement(&mut self) {
+        match self.state().loops.last() {
+            Some(loop_state) => {
+                let (start, scope_depth) = (loop_state.start, loop_state.scope_depth);
+                self.consume(TokenType::SEMICOLON, "Expect ';' after 'continue'.");
+                self.discard_locals(scope_depth);
+                self.emit_loop(start);
+            }
+            None => {
+                self.error("Can't use 'continue' outside of a loop.");
+                self.consume(TokenType::SEMICOLON, "Expect ';' after 'continue'.");
+            }
+        }
+    }
+
+    /// The loop body is compiled next, `continue` in it jumps back to `start`
+    fn begin_loop(&mut self, start: usize) {
+        let scope_depth = *self.local_track().depth();
+        self.state().loops.push(LoopState {
+            start,
+            scope_depth,
+            break_jumps: Vec::new(),
+        });
+    }
+
+    /// Every `break` of the loop lands here, after the loop's own cleanup
+    fn end_loop(&mut self) {
+        if let Some(loop_state) = self.state().loops.pop() {
+            for jump in loop_state.break_jumps {
+                self.patch_jump(jump);
+            }
+        }
+    }
+
+    /// A bare `return;` implicitly returns nil
+    fn return_statement(&mut self) {
+        if self.state().function_type == FunctionType::Script {
+            self.error("Can't return from top-level code.");
+        }
+
+        if self.match_token(TokenType::SEMICOLON) {
+            self.emit_return();
+        } else {
+            if self.state().function_type == FunctionType::Initializer {
+                self.error("Can't return a value from an initializer.");
+            }
+
+            self.expression();
+            self.consume(TokenType::SEMICOLON, "Expect ';' after return value.");
+            self.emit_byte(OpCode::RETURN as u8);
+        }
+    }
+
     fn while_statement(&mut self) {
-        let loop_start = self.chunk.op_codes_len();
+        let loop_start = self.current_chunk().op_codes_len();
         self.consume(TokenType::LEFTPAREN, "Expect '(' after 'while'.");
         self.expression();
         self.consume(TokenType::RIGHTPAREN, "Expect ')' after condition.");
 
         let exit_jump = self.emit_jump(OpCode::JumpIfFalse as u8);
         self.emit_byte(OpCode::POP as u8);
+        self.begin_loop(loop_start);
         self.statement();
         self.emit_loop(loop_start);
 
         self.patch_jump(exit_jump);
         self.emit_byte(OpCode::POP as u8);
+        self.end_loop();
     }
 
     fn print_statement(&mut self) {
@@ -225,12 +572,27 @@ impl<'scanner, 'chunk> Compiler<'scanner, 'chunk> {
 
     fn expression_statement(&mut self) {
         self.expression();
+
+        // The repl echoes values of top-level expressions instead of discarding them
+        let is_repl_echo = self.repl
+            && self.state().function_type == FunctionType::Script
+            && *self.local_track().depth() == 0;
+        if is_repl_echo {
+            if !self.check_token(TokenType::EOF) {
+                self.consume(TokenType::SEMICOLON, "Expect ';' after expression.");
+            }
+            self.emit_byte(OpCode::PRINT.into());
+            return;
+        }
+
         self.consume(TokenType::SEMICOLON, "Expect ';' after expression.");
         self.emit_byte(OpCode::POP.into());
     }
 
     fn advance(&mut self) {
         self.previous = self.current.take();
+        // Doc comments in front of the token just consumed didn't start a declaration
+        self.doc_comment.clear();
         // println!(
         //    "Advance Fn - Prev {:?}, Curr {:?}",
         //    self.previous, self.current
@@ -245,6 +607,8 @@ impl<'scanner, 'chunk> Compiler<'scanner, 'chunk> {
                     self.current = Some(scanned_token);
                     self.error_at_current(&err_message);
                 }
+                // Kept aside for the next declaration, the parser never sees them
+                TokenType::DOCCOMMENT => self.doc_comment.push(scanned_token.lexeme),
                 _ => {
                     self.current = Some(scanned_token);
                     break;
@@ -253,6 +617,16 @@ impl<'scanner, 'chunk> Compiler<'scanner, 'chunk> {
         }
     }
 
+    /// The doc comment lines scanned so far joined into one string
+    fn take_doc_comment(&mut self) -> Option<ObjRef> {
+        if self.doc_comment.is_empty() {
+            return None;
+        }
+
+        let doc = std::mem::take(&mut self.doc_comment).join("\n");
+        Some(self.heap.intern_owned(doc))
+    }
+
     fn expression(&mut self) {
         self.parse_precedence(Precedence::ASSIGNMENT);
     }
@@ -261,73 +635,119 @@ impl<'scanner, 'chunk> Compiler<'scanner, 'chunk> {
     /// Take ownership of the previous token (IDENTIFIER),
     ///     adds the previous token's lexeme to chunk's constant
     /// Return the index of the added constant  
-    fn parse_variable(&mut self, err_msg: &str) -> u8 {
+    fn parse_variable(&mut self, err_msg: &str) -> usize {
         self.consume(TokenType::IDENTIFIER, err_msg);
         self.declare_variable();
-        if *self.local_track.depth() > 0 {
+        if *self.local_track().depth() > 0 {
             return 0;
         }
-        if let Some(prev_token) = self.previous.take() {
+        if let Some(prev_token) = self.previous.clone() {
             return self.identifier_constant(prev_token);
         }
         unreachable!()
     }
 
     fn declare_variable(&mut self) {
-        if *self.local_track.depth() == 0 {
+        if *self.local_track().depth() == 0 {
             return;
         }
 
         if let Some(prev_token) = self.previous.to_owned() {
-            for idx in (0..self.local_track.local_count).rev() {
-                if let Some(ref local) = self.local_track.locals[idx as usize] {
-                    if local.depth < Some(*self.local_track.depth()) {
+            let local_track = self.local_track();
+            let mut is_duplicate = false;
+            for idx in (0..local_track.local_count).rev() {
+                if let Some(ref local) = local_track.locals[idx as usize] {
+                    if local.depth.is_some() && local.depth < Some(*local_track.depth()) {
                         break;
                     }
 
                     if prev_token.is_equal(&local.name) {
-                        self.error("Already a variable with this name in this scope.");
+                        is_duplicate = true;
+                        break;
                     }
                 }
             }
 
+            if is_duplicate {
+                self.error("Already a variable with this name in this scope.");
+            }
+
             self.add_local(prev_token.to_owned());
         }
     }
 
     /// outputs the bytecode instruction that defines the new variable and stores its initial value.
-    fn define_variable(&mut self, global: u8) {
-        if *self.local_track.depth() > 0 {
+    fn define_variable(&mut self, global: usize) {
+        if *self.local_track().depth() > 0 {
             self.mark_initialized();
             return;
         }
-        self.emit_bytes(OpCode::DefineGlobal.into(), global);
+        self.emit_operand_op(OpCode::DefineGlobal, global);
     }
 
+    /// Global function declarations have nothing to mark, they are defined by `DefineGlobal`
     fn mark_initialized(&mut self) {
+        let local_track = self.local_track();
+        if local_track.scope_depth == 0 {
+            return;
+        }
+
+        let scope_depth = local_track.scope_depth;
         if let Some(local_depth) =
-            self.local_track.locals[(self.local_track.local_count - 1) as usize].as_mut()
+            local_track.locals[(local_track.local_count - 1) as usize].as_mut()
         {
-            local_depth.depth = Some(self.local_track.scope_depth);
+            local_depth.depth = Some(scope_depth);
         }
     }
 
     fn parse_number(&mut self, _can_assign: bool) {
         if let Some(prev_token) = self.previous.as_ref() {
-            match prev_token.lexeme.parse::<f64>() {
+            // The scanner already checked the digits, only the separators need removing
+            let digits = prev_token.lexeme.replace('_', "");
+            let radix = match digits.get(..2) {
+                Some("0x" | "0X") => 16,
+                Some("0b" | "0B") => 2,
+                Some("0o" | "0O") => 8,
+                _ => 10,
+            };
+            // Integer literals that would be rounded are rejected rather than silently changed
+            let parsed = match radix {
+                10 if digits.contains(['.', 'e', 'E']) => {
+                    digits.parse::<f64>().map_err(|e| e.to_string())
+                }
+                10 => digits
+                    .parse::<u128>()
+                    .map_err(|_| "Integer literal is too large.".to_string())
+                    .and_then(Self::exact_integer),
+                _ => u64::from_str_radix(&digits[2..], radix)
+                    .map_err(|_| "Integer literal is too large.".to_string())
+                    .and_then(|num_value| Self::exact_integer(num_value as u128)),
+            };
+
+            match parsed {
                 Ok(num_value) => {
                     // self.emit_constant(num_value);
                     self.emit_constant(ValueType::Number(num_value));
                 }
-                Err(e) => {
-                    let err_str = e.to_string();
-                    self.error(&err_str)
-                }
+                Err(e) => self.error(&e),
             }
         }
     }
 
+    fn exact_integer(num_value: u128) -> Result<f64, String> {
+        if is_exact_integer(num_value) {
+            Ok(num_value as f64)
+        } else {
+            Err("Integer literal can't be represented exactly.".to_string())
+        }
+    }
+
     fn parse_grouping(&mut self, _can_assign: bool) {
+        if self.is_arrow_function() {
+            self.arrow_function();
+            return;
+        }
+
         self.expression();
         self.consume(TokenType::RIGHTPAREN, "Expect ')' after expression.");
     }
@@ -338,6 +758,7 @@ impl<'scanner, 'chunk> Compiler<'scanner, 'chunk> {
             match operator_type.token_type {
                 TokenType::MINUS => self.emit_byte(OpCode::NEGATE as u8),
                 TokenType::BANG => self.emit_byte(OpCode::NOT as u8),
+                TokenType::TILDE => self.emit_byte(OpCode::BitNot as u8),
                 _ => (),
             }
         }
@@ -347,7 +768,11 @@ impl<'scanner, 'chunk> Compiler<'scanner, 'chunk> {
         if let Some(operator) = self.previous.take() {
             let operator_type = &operator.token_type;
             let rule = Self::get_rule(*operator_type);
-            let next_precedence = Precedence::from(rule.precedence as u8 + 1);
+            // `**` is right-associative, its right operand may itself be a power
+            let next_precedence = match operator_type {
+                TokenType::STARSTAR => rule.precedence,
+                _ => Precedence::from(rule.precedence as u8 + 1),
+            };
             self.parse_precedence(next_precedence);
             match operator_type {
                 TokenType::BANGEQUAL => self.emit_bytes(OpCode::EQUAL as u8, OpCode::NOT as u8),
@@ -360,11 +785,199 @@ impl<'scanner, 'chunk> Compiler<'scanner, 'chunk> {
                 TokenType::MINUS => self.emit_byte(OpCode::SUBTRACT as u8),
                 TokenType::STAR => self.emit_byte(OpCode::MULTIPLY as u8),
                 TokenType::SLASH => self.emit_byte(OpCode::DIVIDE as u8),
+                TokenType::PERCENT => self.emit_byte(OpCode::MODULO as u8),
+                TokenType::TILDESLASH => self.emit_byte(OpCode::IntDivide as u8),
+                TokenType::STARSTAR => self.emit_byte(OpCode::POWER as u8),
+                TokenType::AMPERSAND => self.emit_byte(OpCode::BitAnd as u8),
+                TokenType::PIPE => self.emit_byte(OpCode::BitOr as u8),
+                TokenType::CARET => self.emit_byte(OpCode::BitXor as u8),
+                TokenType::LESSLESS => self.emit_byte(OpCode::ShiftLeft as u8),
+                TokenType::GREATERGREATER => self.emit_byte(OpCode::ShiftRight as u8),
                 _ => unreachable!(),
             }
         }
     }
 
+    /// The callee is already on the stack, followed by the arguments
+    fn call(&mut self, _can_assign: bool) {
+        let arg_count = self.argument_list();
+        self.emit_bytes(OpCode::CALL as u8, arg_count);
+    }
+
+    fn argument_list(&mut self) -> u8 {
+        let mut arg_count: u8 = 0;
+        if !self.check_token(TokenType::RIGHTPAREN) {
+            loop {
+                self.expression();
+                if arg_count == u8::MAX {
+                    self.error("Can't have more than 255 arguments.");
+                } else {
+                    arg_count += 1;
+                }
+
+                if !self.match_token(TokenType::COMMA) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::RIGHTPAREN, "Expect ')' after arguments.");
+
+        arg_count
+    }
+
+    fn dot(&mut self, can_assign: bool) {
+        self.consume(TokenType::IDENTIFIER, "Expect property name after '.'.");
+        let name = match self.previous.clone() {
+            Some(token) => self.identifier_constant(token),
+            None => return,
+        };
+
+        // A compound assignment reads the property through a copy of the instance,
+        // so the expression producing the instance is evaluated only once
+        match self.match_assignment(can_assign) {
+            Some(Assignment::Plain) => {
+                self.expression();
+                self.emit_operand_op(OpCode::SetProperty, name);
+            }
+            Some(Assignment::Compound(op_code)) => {
+                self.emit_byte(OpCode::DUP as u8);
+                self.emit_operand_op(OpCode::GetProperty, name);
+                self.expression();
+                self.emit_byte(op_code as u8);
+                self.emit_operand_op(OpCode::SetProperty, name);
+            }
+            // The old value is sunk below the instance to be left once the new one is popped
+            Some(Assignment::Postfix(op_code)) => {
+                self.emit_byte(OpCode::DUP as u8);
+                self.emit_operand_op(OpCode::GetProperty, name);
+                self.emit_byte(OpCode::DUP as u8);
+                self.emit_bytes(OpCode::SINK as u8, 2);
+                self.emit_constant(ValueType::Number(1.0));
+                self.emit_byte(op_code as u8);
+                self.emit_operand_op(OpCode::SetProperty, name);
+                self.emit_byte(OpCode::POP as u8);
+            }
+            None => self.emit_operand_op(OpCode::GetProperty, name),
+        }
+    }
+
+    /// `[a, b, c]` evaluates the elements in order and collects them into a new list
+    fn list(&mut self, _can_assign: bool) {
+        let mut item_count: u8 = 0;
+        if !self.check_token(TokenType::RIGHTBRACKET) {
+            loop {
+                self.expression();
+                if item_count == u8::MAX {
+                    self.error("Can't have more than 255 elements in a list literal.");
+                } else {
+                    item_count += 1;
+                }
+
+                if !self.match_token(TokenType::COMMA) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::RIGHTBRACKET, "Expect ']' after list elements.");
+
+        self.emit_bytes(OpCode::BuildList as u8, item_count);
+    }
+
+    /// `{k: v, ...}` evaluates each key and then its value, in order, and collects them into a new map
+    /// At the start of a statement `{` opens a block instead
+    fn map(&mut self, _can_assign: bool) {
+        let mut entry_count: u8 = 0;
+        if !self.check_token(TokenType::RIGHTBRACE) {
+            loop {
+                self.expression();
+                self.consume(TokenType::COLON, "Expect ':' after map key.");
+                self.expression();
+                if entry_count == u8::MAX {
+                    self.error("Can't have more than 255 entries in a map literal.");
+                } else {
+                    entry_count += 1;
+                }
+
+                if !self.match_token(TokenType::COMMA) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::RIGHTBRACE, "Expect '}' after map entries.");
+
+        self.emit_bytes(OpCode::BuildMap as u8, entry_count);
+    }
+
+    /// `a[i]`, or `a[i] = v` when the index is the target of an assignment
+    fn index(&mut self, can_assign: bool) {
+        self.expression();
+        self.consume(TokenType::RIGHTBRACKET, "Expect ']' after index.");
+
+        // Like properties, a compound assignment works on a copy of the target and the index
+        match self.match_assignment(can_assign) {
+            Some(Assignment::Plain) => {
+                self.expression();
+                self.emit_byte(OpCode::SetIndex as u8);
+            }
+            Some(Assignment::Compound(op_code)) => {
+                self.emit_byte(OpCode::DupPair as u8);
+                self.emit_byte(OpCode::GetIndex as u8);
+                self.expression();
+                self.emit_byte(op_code as u8);
+                self.emit_byte(OpCode::SetIndex as u8);
+            }
+            Some(Assignment::Postfix(op_code)) => {
+                self.emit_byte(OpCode::DupPair as u8);
+                self.emit_byte(OpCode::GetIndex as u8);
+                self.emit_byte(OpCode::DUP as u8);
+                self.emit_bytes(OpCode::SINK as u8, 3);
+                self.emit_constant(ValueType::Number(1.0));
+                self.emit_byte(op_code as u8);
+                self.emit_byte(OpCode::SetIndex as u8);
+                self.emit_byte(OpCode::POP as u8);
+            }
+            None => self.emit_byte(OpCode::GetIndex as u8),
+        }
+    }
+
+    /// `this` is resolved like any other local, methods reserve slot zero for the receiver
+    fn this_(&mut self, _can_assign: bool) {
+        if self.classes.is_empty() {
+            self.error("Can't use 'this' outside of a class.");
+            return;
+        }
+
+        self.variable(false);
+    }
+
+    /// Looks up the method on the superclass captured in the `super` local
+    /// and binds it to the current `this`
+    fn super_(&mut self, _can_assign: bool) {
+        match self.classes.last() {
+            None => self.error("Can't use 'super' outside of a class."),
+            Some(class_state) if !class_state.has_superclass => {
+                self.error("Can't use 'super' in a class with no superclass.")
+            }
+            _ => (),
+        }
+
+        self.consume(TokenType::DOT, "Expect '.' after 'super'.");
+        self.consume(TokenType::IDENTIFIER, "Expect superclass method name.");
+        let name = match self.previous.clone() {
+            Some(token) => self.identifier_constant(token),
+            None => return,
+        };
+
+        self.named_variable(Self::synthetic_token(TokenType::THIS, "this"), false);
+        self.named_variable(Self::synthetic_token(TokenType::SUPER, "super"), false);
+        self.emit_operand_op(OpCode::GetSuper, name);
+    }
+
+    /// A token the user never wrote, used to refer to the compiler's own locals
+    fn synthetic_token(token_type: TokenType, lexeme: &str) -> Token {
+        Token::new(token_type, lexeme.to_string(), 0, 0)
+    }
+
     fn parse_literal(&mut self, _can_assign: bool) {
         if let Some(token) = self.previous.as_ref() {
             match token.token_type {
@@ -394,9 +1007,10 @@ impl<'scanner, 'chunk> Compiler<'scanner, 'chunk> {
                         {
                             infix_rule(self, can_assign);
                         }
-                        if can_assign && self.match_token(TokenType::EQUAL) {
-                            self.error("Invalid assignment target.");
-                        }
+                    }
+
+                    if self.match_assignment(can_assign).is_some() {
+                        self.error("Invalid assignment target.");
                     }
                 }
                 None => {
@@ -406,6 +1020,26 @@ impl<'scanner, 'chunk> Compiler<'scanner, 'chunk> {
         }
     }
 
+    /// `cond ? a : b`, the condition is already on the stack
+    /// Only one branch is evaluated, like the two arms of an if statement
+    /// The then branch is delimited by the ':' so it can be any expression, even an assignment
+    /// The else branch is parsed at the same precedence so `a ? b : c ? d : e` nests to the right
+    fn conditional(&mut self, _can_assign: bool) {
+        let else_jump = self.emit_jump(OpCode::JumpIfFalse as u8);
+        self.emit_byte(OpCode::POP as u8);
+        self.expression();
+        self.consume(
+            TokenType::COLON,
+            "Expect ':' after then branch of conditional expression.",
+        );
+
+        let end_jump = self.emit_jump(OpCode::JUMP as u8);
+        self.patch_jump(else_jump);
+        self.emit_byte(OpCode::POP as u8);
+        self.parse_precedence(Precedence::CONDITIONAL);
+        self.patch_jump(end_jump);
+    }
+
     fn and_(&mut self, _can_assign: bool) {
         let end_jump = self.emit_jump(OpCode::JumpIfFalse as u8);
 
@@ -429,11 +1063,54 @@ impl<'scanner, 'chunk> Compiler<'scanner, 'chunk> {
     fn string(&mut self, _can_assign: bool) {
         if let Some(prev_token) = self.previous.as_mut() {
             let str_value = std::mem::take(&mut prev_token.lexeme);
-            let str_obj = ObjString::new(str_value);
-            self.emit_constant(ValueType::Obj(Object::ObjString(str_obj)));
+            let str_obj = self.heap.intern_owned(str_value);
+            self.emit_constant(ValueType::Obj(str_obj));
         }
     }
 
+    /// `"a ${x} b"` compiles as `"a " + x + " b"` with each expression stringified first
+    /// Empty parts of the string are left out
+    fn interpolation(&mut self, _can_assign: bool) {
+        let mut has_prefix = self.string_part();
+        loop {
+            self.expression();
+            self.emit_byte(OpCode::STRINGIFY as u8);
+            if has_prefix {
+                self.emit_byte(OpCode::ADD as u8);
+            }
+            has_prefix = true;
+
+            let done = !self.match_token(TokenType::INTERPOLATION);
+            if done {
+                self.consume(
+                    TokenType::STRING,
+                    "Expect '}' after interpolated expression.",
+                );
+            }
+            if self.string_part() {
+                self.emit_byte(OpCode::ADD as u8);
+            }
+            if done {
+                break;
+            }
+        }
+    }
+
+    /// Emits the string in the previous token unless it's empty, returns whether it did
+    fn string_part(&mut self) -> bool {
+        let Some(prev_token) = self.previous.as_mut() else {
+            return false;
+        };
+        if prev_token.lexeme.is_empty() {
+            return false;
+        }
+
+        let str_value = std::mem::take(&mut prev_token.lexeme);
+        let str_obj = self.heap.intern_owned(str_value);
+        self.emit_constant(ValueType::Obj(str_obj));
+        true
+    }
+
     fn variable(&mut self, can_assign: bool) {
         if let Some(prev_token) = self.previous.as_ref() {
             self.named_variable(prev_token.to_owned(), can_assign);
@@ -441,26 +1118,85 @@ impl<'scanner, 'chunk> Compiler<'scanner, 'chunk> {
     }
 
     fn named_variable(&mut self, token_name: Token, can_assign: bool) {
-        let (arg, get_op, set_op) = match self.resolve_local(&token_name) {
-            Some(arg) => (arg, OpCode::GetLocal, OpCode::SetLocal),
-            None => {
-                let new_arg = self.identifier_constant(token_name);
-                (new_arg, OpCode::GetGlobal, OpCode::SetGlobal)
-            }
+        let current_state = self.states.len() - 1;
+        let name = token_name.lexeme.clone();
+        let (arg, get_op, set_op, is_const) = match self.resolve_local(current_state, &token_name) {
+            Some(arg) => (
+                arg as usize,
+                OpCode::GetLocal,
+                OpCode::SetLocal,
+                self.states[current_state].local_track.is_const(arg),
+            ),
+            None => match self.resolve_upvalue(current_state, &token_name) {
+                Some(arg) => (
+                    arg as usize,
+                    OpCode::GetUpvalue,
+                    OpCode::SetUpvalue,
+                    self.states[current_state].upvalues[arg as usize].is_const,
+                ),
+                // Global constants are only known at runtime
+                None => {
+                    let new_arg = self.identifier_constant(token_name);
+                    (new_arg, OpCode::GetGlobal, OpCode::SetGlobal, false)
+                }
+            },
         };
 
-        match can_assign && self.match_token(TokenType::EQUAL) {
-            true => {
+        // `this` is only read with assignment disallowed, but `this++` still gets here
+        let assignment = self.match_assignment(can_assign);
+        if assignment.is_some() && name == "this" {
+            self.error("Invalid assignment target.");
+        } else if assignment.is_some() && is_const {
+            self.error(&format!("Can't assign to constant '{}'.", name));
+        }
+
+        match assignment {
+            Some(Assignment::Plain) => {
+                self.expression();
+                self.emit_operand_op(set_op, arg);
+            }
+            Some(Assignment::Compound(op_code)) => {
+                self.emit_operand_op(get_op, arg);
                 self.expression();
-                self.emit_bytes(set_op.into(), arg);
+                self.emit_byte(op_code as u8);
+                self.emit_operand_op(set_op, arg);
             }
-            false => self.emit_bytes(get_op.into(), arg),
+            Some(Assignment::Postfix(op_code)) => {
+                self.emit_operand_op(get_op, arg);
+                self.emit_byte(OpCode::DUP as u8);
+                self.emit_constant(ValueType::Number(1.0));
+                self.emit_byte(op_code as u8);
+                self.emit_operand_op(set_op, arg);
+                self.emit_byte(OpCode::POP as u8);
+            }
+            None => self.emit_operand_op(get_op, arg),
         }
     }
 
-    fn resolve_local(&mut self, token_name: &Token) -> Option<u8> {
-        for idx in (0..self.local_track.local_count).rev() {
-            if let Some(local) = self.local_track.locals.get(idx as usize) {
+    /// Consumes the assignment operator following a target, if there is one and assignment is allowed
+    /// `++` and `--` bind tighter than any operator so they are allowed after any target
+    fn match_assignment(&mut self, can_assign: bool) -> Option<Assignment> {
+        let assignment = match self.current.as_ref()?.token_type {
+            TokenType::PLUSPLUS => Assignment::Postfix(OpCode::ADD),
+            TokenType::MINUSMINUS => Assignment::Postfix(OpCode::SUBTRACT),
+            _ if !can_assign => return None,
+            TokenType::EQUAL => Assignment::Plain,
+            TokenType::PLUSEQUAL => Assignment::Compound(OpCode::ADD),
+            TokenType::MINUSEQUAL => Assignment::Compound(OpCode::SUBTRACT),
+            TokenType::STAREQUAL => Assignment::Compound(OpCode::MULTIPLY),
+            TokenType::SLASHEQUAL => Assignment::Compound(OpCode::DIVIDE),
+            _ => return None,
+        };
+        self.advance();
+
+        Some(assignment)
+    }
+
+    /// Looks for the variable among the locals of the function at `state_idx` in the compiler stack
+    fn resolve_local(&mut self, state_idx: usize, token_name: &Token) -> Option<u8> {
+        let local_track = &self.states[state_idx].local_track;
+        for idx in (0..local_track.local_count).rev() {
+            if let Some(local) = local_track.locals.get(idx as usize) {
                 if let Some(local_val) = local.as_ref() {
                     if local_val.name.is_equal(token_name) {
                         if local_val.depth.is_none() {
@@ -475,40 +1211,119 @@ impl<'scanner, 'chunk> Compiler<'scanner, 'chunk> {
         None
     }
 
+    /// Looks for the variable in the enclosing functions
+    /// A local of the directly enclosing function is captured from its stack slot,
+    /// anything further out is threaded through the enclosing function's own upvalues
+    fn resolve_upvalue(&mut self, state_idx: usize, token_name: &Token) -> Option<u8> {
+        if state_idx == 0 {
+            return None;
+        }
+        let enclosing = state_idx - 1;
+
+        if let Some(local_idx) = self.resolve_local(enclosing, token_name) {
+            if let Some(local) =
+                self.states[enclosing].local_track.locals[local_idx as usize].as_mut()
+            {
+                local.is_captured = true;
+            }
+            let is_const = self.states[enclosing].local_track.is_const(local_idx);
+            return Some(self.add_upvalue(state_idx, local_idx, true, is_const));
+        }
+
+        if let Some(upvalue_idx) = self.resolve_upvalue(enclosing, token_name) {
+            let is_const = self.states[enclosing].upvalues[upvalue_idx as usize].is_const;
+            return Some(self.add_upvalue(state_idx, upvalue_idx, false, is_const));
+        }
+
+        None
+    }
+
+    /// Reuses the existing upvalue if the function already captures the same variable
+    fn add_upvalue(&mut self, state_idx: usize, index: u8, is_local: bool, is_const: bool) -> u8 {
+        let upvalues = &self.states[state_idx].upvalues;
+        if let Some(existing) = upvalues
+            .iter()
+            .position(|upvalue| upvalue.index == index && upvalue.is_local == is_local)
+        {
+            return existing as u8;
+        }
+
+        if upvalues.len() == 256 {
+            self.error("Too many closure variables in function.");
+            return 0;
+        }
+
+        let state = &mut self.states[state_idx];
+        state.upvalues.push(Upvalue {
+            index,
+            is_local,
+            is_const,
+        });
+        state.function.upvalue_count = state.upvalues.len();
+
+        (state.upvalues.len() - 1) as u8
+    }
+
     fn begin_scope(&mut self) {
-        self.local_track.begin();
+        self.local_track().begin();
     }
 
     fn end_scope(&mut self) {
-        self.local_track.end();
+        self.local_track().end();
 
-        while let Some(local) = self
-            .local_track
-            .locals
-            .get((self.local_track.local_count.wrapping_sub(1)) as usize)
-        {
-            match local.as_ref() {
-                Some(l) => {
-                    if l.depth <= Some(self.local_track.scope_depth) {
+        loop {
+            let local_track = self.local_track();
+            let scope_depth = local_track.scope_depth;
+            match local_track
+                .locals
+                .get((local_track.local_count.wrapping_sub(1)) as usize)
+            {
+                Some(Some(l)) => {
+                    if l.depth <= Some(scope_depth) {
                         break;
                     }
-                    self.emit_byte(OpCode::POP as u8);
-                    self.local_track.local_count -= 1;
+                    // Captured locals are moved to the heap instead of being discarded
+                    let op_code = match l.is_captured {
+                        true => OpCode::CloseUpvalue,
+                        false => OpCode::POP,
+                    };
+                    local_track.local_count -= 1;
+                    self.emit_byte(op_code as u8);
                 }
-                None => break,
+                _ => break,
             }
         }
     }
 
+    /// Emits the pops for the locals deeper than `depth` but keeps tracking them,
+    /// for jumps out of scopes that the compiler has not finished yet
+    fn discard_locals(&mut self, depth: u8) {
+        let local_track = self.local_track();
+        let op_codes: Vec<OpCode> = local_track.locals[..local_track.local_count as usize]
+            .iter()
+            .rev()
+            .flatten()
+            .take_while(|local| local.depth.is_none_or(|local_depth| local_depth > depth))
+            .map(|local| match local.is_captured {
+                true => OpCode::CloseUpvalue,
+                false => OpCode::POP,
+            })
+            .collect();
+
+        for op_code in op_codes {
+            self.emit_byte(op_code as u8);
+        }
+    }
+
     /// takes the given token and adds its lexeme to the chunk’s constant table as a string object.
-    fn identifier_constant(&mut self, mut token: Token) -> u8 {
+    fn identifier_constant(&mut self, mut token: Token) -> usize {
         let str_value = std::mem::take(&mut token.lexeme);
-        let str_obj = ObjString::new(str_value);
-        self.make_constant(ValueType::Obj(Object::ObjString(str_obj)))
+        let str_obj = self.heap.intern_owned(str_value);
+        self.make_constant(ValueType::Obj(str_obj))
     }
 
     fn add_local(&mut self, name: Token) {
-        if self.local_track.local_count == 255 {
+        if self.local_track().local_count == 255 {
             self.error("Too many local variables in function.");
             return;
         }
@@ -517,36 +1332,58 @@ impl<'scanner, 'chunk> Compiler<'scanner, 'chunk> {
             // depth: *self.local_track.depth(),
             depth: None,
             name,
+            is_captured: false,
+            is_const: false,
         };
 
-        self.local_track
-            .add_local_at_idx(local, self.local_track.local_count);
-        self.local_track.local_count += 1;
+        let local_track = self.local_track();
+        local_track.add_local_at_idx(local, local_track.local_count);
+        local_track.local_count += 1;
     }
 
     /// Adds the ValueType to the chunk->constants and gets the index
     /// Adds the `OpCode::CONSTANT`(u8) and `index` in the chunk->opcodes
     fn emit_constant(&mut self, value: ValueType) {
-        let cons_byte_idx = self.make_constant(value);
-        self.emit_bytes(OpCode::CONSTANT as u8, cons_byte_idx);
+        let cons_idx = self.make_constant(value);
+        self.emit_operand_op(OpCode::CONSTANT, cons_idx);
     }
 
     /// Add the ValueType to chunk->constants and returns the index
-    fn make_constant(&mut self, value: ValueType) -> u8 {
-        let constant = self.chunk.add_constant(value);
+    fn make_constant(&mut self, value: ValueType) -> usize {
+        let constant = self.current_chunk().add_constant(value);
 
-        if constant > 255 {
+        if constant >= MAX_CONSTANTS {
             self.error("Too many constants in one chunk.");
             return 0;
         }
 
-        constant as u8
+        constant
+    }
+
+    /// Emits the instruction followed by its one byte operand
+    /// Operands that don't fit in a byte switch to the long form of the instruction,
+    /// which takes a 24-bit operand (high byte first)
+    fn emit_operand_op(&mut self, op_code: OpCode, operand: usize) {
+        if operand <= u8::MAX as usize {
+            self.emit_bytes(op_code.into(), operand as u8);
+            return;
+        }
+
+        match op_code.long_form() {
+            Some(long_op_code) => {
+                self.emit_byte(long_op_code.into());
+                self.emit_byte(((operand >> 16) & 0xff) as u8);
+                self.emit_byte(((operand >> 8) & 0xff) as u8);
+                self.emit_byte((operand & 0xff) as u8);
+            }
+            None => self.error("Too many constants in one chunk."),
+        }
     }
 
     pub fn emit_loop(&mut self, loop_start: usize) {
         self.emit_byte(OpCode::LOOP as u8);
 
-        let offset = self.chunk.op_codes_len() - loop_start + 2;
+        let offset = self.current_chunk().op_codes_len() - loop_start + 2;
         if offset > u16::MAX as usize {
             self.error("Loop body too large.");
         }
@@ -562,28 +1399,28 @@ impl<'scanner, 'chunk> Compiler<'scanner, 'chunk> {
         self.emit_byte(0xff);
         self.emit_byte(0xff);
 
-        self.chunk.op_codes_len() - 2
+        self.current_chunk().op_codes_len() - 2
     }
 
     pub fn patch_jump(&mut self, offset: usize) {
-        let jump = self.chunk.op_codes_len() - offset - 2;
+        let jump = self.current_chunk().op_codes_len() - offset - 2;
 
         if jump > u16::MAX as usize {
             self.error("Too much code to jump over.");
         }
 
-        if let Some(first_jump) = self.chunk.op_codes_at_mut(offset) {
+        if let Some(first_jump) = self.current_chunk().op_codes_at_mut(offset) {
             *first_jump = ((jump >> 8) & 0xff) as u8;
         }
 
-        if let Some(next_jump) = self.chunk.op_codes_at_mut(offset + 1) {
+        if let Some(next_jump) = self.current_chunk().op_codes_at_mut(offset + 1) {
             *next_jump = (jump & 0xff) as u8;
         }
     }
 
     pub fn emit_byte(&mut self, byte: u8) {
-        if let Some(prev_token) = self.previous.as_ref() {
-            self.chunk.write(byte, prev_token.line);
+        if let Some(line) = self.previous.as_ref().map(|token| token.line) {
+            self.current_chunk().write(byte, line);
         }
     }
 
@@ -592,17 +1429,50 @@ impl<'scanner, 'chunk> Compiler<'scanner, 'chunk> {
         self.emit_byte(byte_b);
     }
 
-    fn end_compiler(&mut self) {
+    /// Finishes the innermost function, moves it to the heap and hands back its handle
+    /// along with the upvalues the closure needs to capture
+    fn end_compiler(&mut self) -> (ObjRef, Vec<Upvalue>) {
         self.emit_return();
-        if self.debug && !self.had_error {
-            disassemble_chunk(self.chunk, "code");
+        let state = self
+            .states
+            .pop()
+            .expect("[Compiler] no function state to end");
+
+        if self.debug && !self.had_error() {
+            let name = match state.function.name {
+                Some(fn_name) => self.heap.as_str(fn_name).to_string(),
+                None => "<script>".to_string(),
+            };
+            disassemble_chunk(self.heap, &state.function.chunk, &name);
         }
+
+        let function = self.heap.alloc(Object::ObjFunction(state.function));
+        (function, state.upvalues)
     }
 
+    /// Initializers always return the instance, which lives in slot zero
     fn emit_return(&mut self) {
+        match self.state().function_type {
+            FunctionType::Initializer => self.emit_bytes(OpCode::GetLocal as u8, 0),
+            _ => self.emit_byte(OpCode::NIL as u8),
+        }
         self.emit_byte(OpCode::RETURN as u8);
     }
 
+    fn state(&mut self) -> &mut FunctionState {
+        self.states
+            .last_mut()
+            .expect("[Compiler] no function is being compiled")
+    }
+
+    fn current_chunk(&mut self) -> &mut Chunk {
+        &mut self.state().function.chunk
+    }
+
+    fn local_track(&mut self) -> &mut LocalTracking {
+        &mut self.state().local_track
+    }
+
     /// Checks if the current token doesn't matches the given then returns false
     /// If it matches then, advances the compiler and return true
     fn match_token(&mut self, expected_token: TokenType) -> bool {
@@ -654,28 +1524,42 @@ impl<'scanner, 'chunk> Compiler<'scanner, 'chunk> {
         }
 
         self.panic_mode = true;
-        eprint!("[line {} => Error ", token.line);
 
-        match token.token_type {
-            TokenType::EOF => eprint!("at end"),
-            TokenType::ERROR => (),
-            _ => eprint!("at {}", token.lexeme),
-        }
+        // Error tokens carry the scanner's message as lexeme, there is no source text to point at
+        let lexeme = match token.token_type {
+            TokenType::EOF => Some(String::new()),
+            TokenType::ERROR => None,
+            _ => Some(token.lexeme.clone()),
+        };
 
-        println!(": {}", msg);
-        self.had_error = true;
+        self.diagnostics.push(Diagnostic {
+            line: token.line,
+            column: token.column,
+            lexeme,
+            message: msg.to_string(),
+        });
     }
 
-    fn get_rule(token_type: TokenType) -> ParseRule<'scanner, 'chunk> {
+    fn get_rule(token_type: TokenType) -> ParseRule<'scanner, 'heap> {
         match token_type {
-            TokenType::LEFTPAREN => {
-                ParseRule::new(Some(Self::parse_grouping), None, Precedence::NONE)
-            }
+            TokenType::LEFTPAREN => ParseRule::new(
+                Some(Self::parse_grouping),
+                Some(Self::call),
+                Precedence::CALL,
+            ),
             TokenType::RIGHTPAREN => ParseRule::default(),
-            TokenType::LEFTBRACE => ParseRule::default(),
+            TokenType::LEFTBRACE => ParseRule::new(Some(Self::map), None, Precedence::NONE),
             TokenType::RIGHTBRACE => ParseRule::default(),
+            TokenType::LEFTBRACKET => {
+                ParseRule::new(Some(Self::list), Some(Self::index), Precedence::CALL)
+            }
+            TokenType::RIGHTBRACKET => ParseRule::default(),
+            TokenType::COLON => ParseRule::default(),
+            TokenType::QUESTION => {
+                ParseRule::new(None, Some(Self::conditional), Precedence::CONDITIONAL)
+            }
             TokenType::COMMA => ParseRule::default(),
-            TokenType::DOT => ParseRule::default(),
+            TokenType::DOT => ParseRule::new(None, Some(Self::dot), Precedence::CALL),
             TokenType::MINUS => ParseRule::new(
                 Some(Self::parse_unary),
                 Some(Self::parse_binary),
@@ -685,6 +1569,33 @@ impl<'scanner, 'chunk> Compiler<'scanner, 'chunk> {
             TokenType::SEMICOLON => ParseRule::default(),
             TokenType::SLASH => ParseRule::new(None, Some(Self::parse_binary), Precedence::FACTOR),
             TokenType::STAR => ParseRule::new(None, Some(Self::parse_binary), Precedence::FACTOR),
+            TokenType::STARSTAR => {
+                ParseRule::new(None, Some(Self::parse_binary), Precedence::POWER)
+            }
+            TokenType::TILDESLASH => {
+                ParseRule::new(None, Some(Self::parse_binary), Precedence::FACTOR)
+            }
+            TokenType::AMPERSAND => {
+                ParseRule::new(None, Some(Self::parse_binary), Precedence::BITAND)
+            }
+            TokenType::PIPE => ParseRule::new(None, Some(Self::parse_binary), Precedence::BITOR),
+            TokenType::CARET => ParseRule::new(None, Some(Self::parse_binary), Precedence::BITXOR),
+            TokenType::TILDE => ParseRule::new(Some(Self::parse_unary), None, Precedence::NONE),
+            TokenType::LESSLESS => {
+                ParseRule::new(None, Some(Self::parse_binary), Precedence::SHIFT)
+            }
+            TokenType::GREATERGREATER => {
+                ParseRule::new(None, Some(Self::parse_binary), Precedence::SHIFT)
+            }
+            TokenType::PLUSEQUAL => ParseRule::default(),
+            TokenType::PLUSPLUS => ParseRule::default(),
+            TokenType::MINUSEQUAL => ParseRule::default(),
+            TokenType::MINUSMINUS => ParseRule::default(),
+            TokenType::STAREQUAL => ParseRule::default(),
+            TokenType::SLASHEQUAL => ParseRule::default(),
+            TokenType::PERCENT => {
+                ParseRule::new(None, Some(Self::parse_binary), Precedence::FACTOR)
+            }
             TokenType::BANG => ParseRule::new(Some(Self::parse_unary), None, Precedence::NONE),
             TokenType::BANGEQUAL => {
                 ParseRule::new(None, Some(Self::parse_binary), Precedence::EQUALITY)
@@ -715,20 +1626,28 @@ impl<'scanner, 'chunk> Compiler<'scanner, 'chunk> {
                 infix: None,
                 precedence: Precedence::NONE,
             },
+            TokenType::DOCCOMMENT => ParseRule::default(),
+            TokenType::ARROW => ParseRule::default(),
+            TokenType::INTERPOLATION => {
+                ParseRule::new(Some(Self::interpolation), None, Precedence::NONE)
+            }
             TokenType::NUMBER => ParseRule::new(Some(Self::parse_number), None, Precedence::NONE),
             TokenType::AND => ParseRule::new(None, Some(Self::and_), Precedence::AND),
+            TokenType::BREAK => ParseRule::default(),
             TokenType::CLASS => ParseRule::default(),
+            TokenType::CONST => ParseRule::default(),
+            TokenType::CONTINUE => ParseRule::default(),
             TokenType::ELSE => ParseRule::default(),
             TokenType::FALSE => ParseRule::new(Some(Self::parse_literal), None, Precedence::NONE),
             TokenType::FOR => ParseRule::default(),
-            TokenType::FUN => ParseRule::default(),
+            TokenType::FUN => ParseRule::new(Some(Self::lambda), None, Precedence::NONE),
             TokenType::IF => ParseRule::default(),
             TokenType::NIL => ParseRule::new(Some(Self::parse_literal), None, Precedence::NONE),
-            TokenType::OR => ParseRule::new(None, Some(Self::or_), Precedence::AND),
+            TokenType::OR => ParseRule::new(None, Some(Self::or_), Precedence::OR),
             TokenType::PRINT => ParseRule::default(),
             TokenType::RETURN => ParseRule::default(),
-            TokenType::SUPER => ParseRule::default(),
-            TokenType::THIS => ParseRule::default(),
+            TokenType::SUPER => ParseRule::new(Some(Self::super_), None, Precedence::NONE),
+            TokenType::THIS => ParseRule::new(Some(Self::this_), None, Precedence::NONE),
             TokenType::TRUE => ParseRule::new(Some(Self::parse_literal), None, Precedence::NONE),
             TokenType::VAR => ParseRule::default(),
             TokenType::WHILE => ParseRule::default(),
@@ -738,19 +1657,19 @@ impl<'scanner, 'chunk> Compiler<'scanner, 'chunk> {
     }
 }
 
-type ParseFn<'scanner, 'chunk> = fn(&mut Compiler<'scanner, 'chunk>, bool) -> ();
+type ParseFn<'scanner, 'heap> = fn(&mut Compiler<'scanner, 'heap>, bool) -> ();
 
 #[derive(Default)]
-pub struct ParseRule<'scanner, 'chunk> {
-    prefix: Option<ParseFn<'scanner, 'chunk>>,
-    infix: Option<ParseFn<'scanner, 'chunk>>,
+pub struct ParseRule<'scanner, 'heap> {
+    prefix: Option<ParseFn<'scanner, 'heap>>,
+    infix: Option<ParseFn<'scanner, 'heap>>,
     precedence: Precedence,
 }
 
-impl<'scanner, 'chunk> ParseRule<'scanner, 'chunk> {
+impl<'scanner, 'heap> ParseRule<'scanner, 'heap> {
     pub fn new(
-        prefix: Option<ParseFn<'scanner, 'chunk>>,
-        infix: Option<ParseFn<'scanner, 'chunk>>,
+        prefix: Option<ParseFn<'scanner, 'heap>>,
+        infix: Option<ParseFn<'scanner, 'heap>>,
         precedence: Precedence,
     ) -> Self {
         ParseRule {
@@ -761,6 +1680,77 @@ impl<'scanner, 'chunk> ParseRule<'scanner, 'chunk> {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FunctionType {
+    Function,
+    Initializer,
+    Method,
+    Script,
+}
+
+/// Per function compilation state, the compiler keeps a stack of these
+/// with the innermost function being compiled at the top
+pub struct FunctionState {
+    function: ObjFunction,
+    function_type: FunctionType,
+    local_track: LocalTracking,
+    upvalues: Vec<Upvalue>,
+    /// Loops enclosing the code being compiled, innermost at the top
+    /// They belong to the function, a nested function can't break out of them
+    loops: Vec<LoopState>,
+}
+
+impl FunctionState {
+    pub fn new(function_type: FunctionType, name: Option<ObjRef>) -> Self {
+        let mut function = ObjFunction::new_function();
+        function.name = name;
+
+        // Slot zero of every call frame holds the function being called,
+        // or the receiver for methods which is then accessible as `this`
+        let slot_zero_name = match function_type {
+            FunctionType::Method | FunctionType::Initializer => "this".to_string(),
+            _ => String::new(),
+        };
+        let mut local_track = LocalTracking::default();
+        let slot_zero = Local {
+            name: Token::new(TokenType::IDENTIFIER, slot_zero_name, 0, 0),
+            depth: Some(0),
+            is_captured: false,
+            is_const: false,
+        };
+        local_track.add_local_at_idx(slot_zero, 0);
+        local_track.local_count = 1;
+
+        FunctionState {
+            function,
+            function_type,
+            local_track,
+            upvalues: Vec::new(),
+            loops: Vec::new(),
+        }
+    }
+}
+
+pub struct ClassState {
+    has_superclass: bool,
+}
+
+/// A loop enclosing the code being compiled, `start` is where `continue` jumps to
+/// Locals deeper than `scope_depth` belong to the body and are popped by `break` and `continue`
+pub struct LoopState {
+    start: usize,
+    scope_depth: u8,
+    break_jumps: Vec<usize>,
+}
+
+/// `=` replaces the target, the compound forms like `+=` combine it with the value first
+/// `x++` and `x--` store the target plus or minus one but evaluate to its old value
+pub enum Assignment {
+    Plain,
+    Compound(OpCode),
+    Postfix(OpCode),
+}
+
 pub struct LocalTracking {
     locals: [Option<Local>; 256],
     local_count: u8,
@@ -793,11 +1783,29 @@ impl LocalTracking {
     pub fn depth(&self) -> &u8 {
         &self.scope_depth
     }
+
+    pub fn is_const(&self, idx: u8) -> bool {
+        self.locals[idx as usize]
+            .as_ref()
+            .is_some_and(|local| local.is_const)
+    }
 }
 
 pub struct Local {
     name: Token,
     depth: Option<u8>,
+    is_captured: bool,
+    /// Declared with `const`, assigning it is a compile error
+    is_const: bool,
+}
+
+/// `is_local` tells if the upvalue captures a local of the enclosing function (by slot)
+/// or one of the enclosing function's own upvalues (by index)
+pub struct Upvalue {
+    index: u8,
+    is_local: bool,
+    /// The captured variable is a constant
+    is_const: bool,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Default)]
@@ -805,15 +1813,21 @@ pub struct Local {
 pub enum Precedence {
     #[default]
     NONE,
-    ASSIGNMENT, // =
-    OR,         // or
-    AND,        // and
-    EQUALITY,   // == !=
-    COMPARISON, // < > <= >=
-    TERM,       // + -
-    FACTOR,     // * /
-    UNARY,      // ! -
-    CALL,       // . ()
+    ASSIGNMENT,  // =
+    CONDITIONAL, // ?:
+    OR,          // or
+    AND,         // and
+    EQUALITY,    // == !=
+    COMPARISON,  // < > <= >=
+    BITOR,       // |
+    BITXOR,      // ^
+    BITAND,      // &
+    SHIFT,       // << >>
+    TERM,        // + -
+    FACTOR,      // * / % ~/
+    UNARY,       // ! - ~
+    POWER,       // **
+    CALL,        // . () []
     PRIMARY,
 }
 
@@ -821,15 +1835,21 @@ impl From<u8> for Precedence {
     fn from(value: u8) -> Self {
         match value {
             1 => Precedence::ASSIGNMENT,
-            2 => Precedence::OR,
-            3 => Precedence::AND,
-            4 => Precedence::EQUALITY,
-            5 => Precedence::COMPARISON,
-            6 => Precedence::TERM,
-            7 => Precedence::FACTOR,
-            8 => Precedence::UNARY,
-            9 => Precedence::CALL,
-            10 => Precedence::PRIMARY,
+            2 => Precedence::CONDITIONAL,
+            3 => Precedence::OR,
+            4 => Precedence::AND,
+            5 => Precedence::EQUALITY,
+            6 => Precedence::COMPARISON,
+            7 => Precedence::BITOR,
+            8 => Precedence::BITXOR,
+            9 => Precedence::BITAND,
+            10 => Precedence::SHIFT,
+            11 => Precedence::TERM,
+            12 => Precedence::FACTOR,
+            13 => Precedence::UNARY,
+            14 => Precedence::POWER,
+            15 => Precedence::CALL,
+            16 => Precedence::PRIMARY,
             _ => Precedence::NONE,
         }
     }
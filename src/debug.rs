@@ -1,6 +1,6 @@
-use crate::{chunk::Chunk, opcode::OpCode};
+use crate::{chunk::Chunk, heap::Heap, object::Object, opcode::OpCode, value::ValueType};
 
-pub fn disassemble_chunk(chunk: &Chunk, name: &str) {
+pub fn disassemble_chunk(heap: &Heap, chunk: &Chunk, name: &str) {
     println!("== {} ==", name);
 
     let mut offset = 0;
@@ -9,11 +9,11 @@ pub fn disassemble_chunk(chunk: &Chunk, name: &str) {
             break;
         }
 
-        offset = disassemble_instruction(chunk, &offset);
+        offset = disassemble_instruction(heap, chunk, &offset);
     }
 }
 
-pub fn disassemble_instruction(chunk: &Chunk, offset: &usize) -> usize {
+pub fn disassemble_instruction(heap: &Heap, chunk: &Chunk, offset: &usize) -> usize {
     print!("{:04}", offset);
 
     let curr_line = chunk.line_from_offset(*offset);
@@ -26,13 +26,26 @@ pub fn disassemble_instruction(chunk: &Chunk, offset: &usize) -> usize {
     let instruction = chunk.op_codes_at(*offset);
     match OpCode::try_from(instruction) {
         Ok(o) => match o {
-            OpCode::CONSTANT => constant_instruction("OP_CONSTANT", chunk, offset),
+            OpCode::CONSTANT => constant_instruction("OP_CONSTANT", heap, chunk, offset),
             OpCode::NEGATE => simple_instruction("OP_NEGATE", offset),
             OpCode::RETURN => simple_instruction("OP_RETURN", offset),
             OpCode::ADD => simple_instruction("OP_ADD", offset),
             OpCode::SUBTRACT => simple_instruction("OP_SUBTRACT", offset),
             OpCode::MULTIPLY => simple_instruction("OP_MULTIPLY", offset),
             OpCode::DIVIDE => simple_instruction("OP_DIVIDE", offset),
+            OpCode::MODULO => simple_instruction("OP_MODULO", offset),
+            OpCode::IntDivide => simple_instruction("OP_INT_DIVIDE", offset),
+            OpCode::POWER => simple_instruction("OP_POWER", offset),
+            OpCode::BitAnd => simple_instruction("OP_BIT_AND", offset),
+            OpCode::BitOr => simple_instruction("OP_BIT_OR", offset),
+            OpCode::BitXor => simple_instruction("OP_BIT_XOR", offset),
+            OpCode::BitNot => simple_instruction("OP_BIT_NOT", offset),
+            OpCode::ShiftLeft => simple_instruction("OP_SHIFT_LEFT", offset),
+            OpCode::ShiftRight => simple_instruction("OP_SHIFT_RIGHT", offset),
+            OpCode::DUP => simple_instruction("OP_DUP", offset),
+            OpCode::DupPair => simple_instruction("OP_DUP_PAIR", offset),
+            OpCode::SINK => byte_instruction("OP_SINK", chunk, offset),
+            OpCode::STRINGIFY => simple_instruction("OP_STRINGIFY", offset),
             OpCode::NIL => simple_instruction("OP_NIL", offset),
             OpCode::TRUE => simple_instruction("OP_TRUE", offset),
             OpCode::FALSE => simple_instruction("OP_FALSE", offset),
@@ -42,14 +55,65 @@ pub fn disassemble_instruction(chunk: &Chunk, offset: &usize) -> usize {
             OpCode::LESS => simple_instruction("OP_LESS", offset),
             OpCode::PRINT => simple_instruction("OP_PRINT", offset),
             OpCode::POP => simple_instruction("OP_POP", offset),
-            OpCode::DefineGlobal => constant_instruction("OP_DEFINE_GLOBAL", chunk, offset),
-            OpCode::GetGlobal => constant_instruction("OP_GET_GLOBAL", chunk, offset),
-            OpCode::SetGlobal => constant_instruction("OP_SET_GLOBAL", chunk, offset),
+            OpCode::DefineGlobal => constant_instruction("OP_DEFINE_GLOBAL", heap, chunk, offset),
+            OpCode::DefineConstGlobal => {
+                constant_instruction("OP_DEFINE_CONST_GLOBAL", heap, chunk, offset)
+            }
+            OpCode::GetGlobal => constant_instruction("OP_GET_GLOBAL", heap, chunk, offset),
+            OpCode::SetGlobal => constant_instruction("OP_SET_GLOBAL", heap, chunk, offset),
             OpCode::GetLocal => byte_instruction("OP_GET_LOCAL", chunk, offset),
             OpCode::SetLocal => byte_instruction("OP_SET_LOCAL", chunk, offset),
             OpCode::JUMP => jump_instruction("OP_JUMP", 1, chunk, offset),
             OpCode::JumpIfFalse => jump_instruction("OP_JUMP_IF_FALSE", 1, chunk, offset),
             OpCode::LOOP => jump_instruction("OP_LOOP", -1, chunk, offset),
+            OpCode::CALL => byte_instruction("OP_CALL", chunk, offset),
+            OpCode::CLOSURE => closure_instruction("OP_CLOSURE", heap, chunk, offset, false),
+            OpCode::ClosureLong => {
+                closure_instruction("OP_CLOSURE_LONG", heap, chunk, offset, true)
+            }
+            OpCode::GetUpvalue => byte_instruction("OP_GET_UPVALUE", chunk, offset),
+            OpCode::SetUpvalue => byte_instruction("OP_SET_UPVALUE", chunk, offset),
+            OpCode::CloseUpvalue => simple_instruction("OP_CLOSE_UPVALUE", offset),
+            OpCode::CLASS => constant_instruction("OP_CLASS", heap, chunk, offset),
+            OpCode::ClassDoc => constant_instruction("OP_CLASS_DOC", heap, chunk, offset),
+            OpCode::GetProperty => constant_instruction("OP_GET_PROPERTY", heap, chunk, offset),
+            OpCode::SetProperty => constant_instruction("OP_SET_PROPERTY", heap, chunk, offset),
+            OpCode::METHOD => constant_instruction("OP_METHOD", heap, chunk, offset),
+            OpCode::INHERIT => simple_instruction("OP_INHERIT", offset),
+            OpCode::GetSuper => constant_instruction("OP_GET_SUPER", heap, chunk, offset),
+            OpCode::ClassLong => constant_long_instruction("OP_CLASS_LONG", heap, chunk, offset),
+            OpCode::ClassDocLong => {
+                constant_long_instruction("OP_CLASS_DOC_LONG", heap, chunk, offset)
+            }
+            OpCode::MethodLong => constant_long_instruction("OP_METHOD_LONG", heap, chunk, offset),
+            OpCode::GetPropertyLong => {
+                constant_long_instruction("OP_GET_PROPERTY_LONG", heap, chunk, offset)
+            }
+            OpCode::SetPropertyLong => {
+                constant_long_instruction("OP_SET_PROPERTY_LONG", heap, chunk, offset)
+            }
+            OpCode::GetSuperLong => {
+                constant_long_instruction("OP_GET_SUPER_LONG", heap, chunk, offset)
+            }
+            OpCode::ConstantLong => {
+                constant_long_instruction("OP_CONSTANT_LONG", heap, chunk, offset)
+            }
+            OpCode::DefineGlobalLong => {
+                constant_long_instruction("OP_DEFINE_GLOBAL_LONG", heap, chunk, offset)
+            }
+            OpCode::DefineConstGlobalLong => {
+                constant_long_instruction("OP_DEFINE_CONST_GLOBAL_LONG", heap, chunk, offset)
+            }
+            OpCode::GetGlobalLong => {
+                constant_long_instruction("OP_GET_GLOBAL_LONG", heap, chunk, offset)
+            }
+            OpCode::SetGlobalLong => {
+                constant_long_instruction("OP_SET_GLOBAL_LONG", heap, chunk, offset)
+            }
+            OpCode::BuildList => byte_instruction("OP_BUILD_LIST", chunk, offset),
+            OpCode::GetIndex => simple_instruction("OP_GET_INDEX", offset),
+            OpCode::SetIndex => simple_instruction("OP_SET_INDEX", offset),
+            OpCode::BuildMap => byte_instruction("OP_BUILD_MAP", chunk, offset),
         },
         Err(_) => {
             eprintln!("Unknown OpCode: `invalid instruction received while converting to opcode`");
@@ -58,6 +122,54 @@ pub fn disassemble_instruction(chunk: &Chunk, offset: &usize) -> usize {
     }
 }
 
+/// Closure is followed by the function constant and then a pair of bytes for each upvalue
+/// The upvalue pairs follow the function operand, which takes three bytes in the long form
+fn closure_instruction(
+    instruction_name: &str,
+    heap: &Heap,
+    chunk: &Chunk,
+    offset: &usize,
+    long: bool,
+) -> usize {
+    let (constant_idx, operand_len) = match long {
+        true => (
+            ((chunk.op_codes_at(*offset + 1) as usize) << 16)
+                | ((chunk.op_codes_at(*offset + 2) as usize) << 8)
+                | (chunk.op_codes_at(*offset + 3) as usize),
+            3,
+        ),
+        false => (chunk.op_codes_at(*offset + 1) as usize, 1),
+    };
+    let function = chunk.get_constant(constant_idx);
+    println!(
+        "{:<16} {:4} {}",
+        instruction_name,
+        constant_idx,
+        heap.value_to_string(&function)
+    );
+
+    let mut offset = *offset + 1 + operand_len;
+    if let ValueType::Obj(function) = function {
+        let upvalue_count = match heap.get(function) {
+            Object::ObjFunction(function) => function.upvalue_count,
+            _ => 0,
+        };
+        for _ in 0..upvalue_count {
+            let is_local = chunk.op_codes_at(offset);
+            let index = chunk.op_codes_at(offset + 1);
+            println!(
+                "{:04}    |                     {} {}",
+                offset,
+                if is_local == 1 { "local" } else { "upvalue" },
+                index
+            );
+            offset += 2;
+        }
+    }
+
+    offset
+}
+
 fn jump_instruction(instruction_name: &str, sign: isize, chunk: &Chunk, offset: &usize) -> usize {
     let high = chunk.op_codes_at(offset + 1);
     let low = chunk.op_codes_at(offset + 2);
@@ -74,8 +186,8 @@ fn jump_instruction(instruction_name: &str, sign: isize, chunk: &Chunk, offset:
 }
 
 fn byte_instruction(instruction_name: &str, chunk: &Chunk, offset: &usize) -> usize {
-    let constant_idx = chunk.op_codes_at(*offset + 1);
-    print!("{:<16} {:4} ", instruction_name, constant_idx);
+    let slot = chunk.op_codes_at(*offset + 1);
+    println!("{:<16} {:4}", instruction_name, slot);
 
     *offset + 2
 }
@@ -85,10 +197,33 @@ fn simple_instruction(instruction_name: &str, offset: &usize) -> usize {
     *offset + 1
 }
 
-fn constant_instruction(instruction_name: &str, chunk: &Chunk, offset: &usize) -> usize {
+fn constant_instruction(
+    instruction_name: &str,
+    heap: &Heap,
+    chunk: &Chunk,
+    offset: &usize,
+) -> usize {
     let constant_idx = chunk.op_codes_at(*offset + 1);
     print!("{:<16} {:4} ", instruction_name, constant_idx);
-    println!("'{}'", &chunk.get_constant(constant_idx as usize));
+    let constant = chunk.get_constant(constant_idx as usize);
+    println!("'{}'", heap.value_to_string(&constant));
 
     *offset + 2
 }
+
+/// The long form stores the constant index in the next three bytes, high byte first
+fn constant_long_instruction(
+    instruction_name: &str,
+    heap: &Heap,
+    chunk: &Chunk,
+    offset: &usize,
+) -> usize {
+    let constant_idx = ((chunk.op_codes_at(*offset + 1) as usize) << 16)
+        | ((chunk.op_codes_at(*offset + 2) as usize) << 8)
+        | (chunk.op_codes_at(*offset + 3) as usize);
+    print!("{:<16} {:4} ", instruction_name, constant_idx);
+    let constant = chunk.get_constant(constant_idx);
+    println!("'{}'", heap.value_to_string(&constant));
+
+    *offset + 4
+}
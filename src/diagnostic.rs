@@ -0,0 +1,26 @@
+use std::fmt::Display;
+
+/// An error found while compiling the source
+/// `lexeme` is the text of the offending token, it is empty when the problem is at the end
+/// of the source and `None` when the scanner could not produce a token at all
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub line: usize,
+    pub column: usize,
+    pub lexeme: Option<String>,
+    pub message: String,
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[line {}:{}] Error", self.line, self.column)?;
+
+        match self.lexeme.as_deref() {
+            Some("") => write!(f, " at end")?,
+            Some(lexeme) => write!(f, " at '{}'", lexeme)?,
+            None => (),
+        }
+
+        write!(f, ": {}", self.message)
+    }
+}
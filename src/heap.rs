@@ -0,0 +1,503 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Display;
+use std::hash::{Hash, Hasher};
+use std::time::Duration;
+
+use crate::object::{
+    ObjClass, ObjClosure, ObjFunction, ObjInstance, ObjList, ObjMap, ObjString, ObjUpvalue, Object,
+};
+use crate::value::ValueType;
+
+/// Bytes that can be allocated before the first collection
+const GC_INITIAL_THRESHOLD: usize = 1024 * 1024;
+/// After a collection the next one happens once the surviving bytes grow by this factor
+const GC_HEAP_GROW_FACTOR: usize = 2;
+
+/// Handle to an object on the heap
+/// Handles are plain indices, copying one does not keep the object alive,
+/// only being reachable from the VM roots does
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ObjRef(usize);
+
+impl Hash for ObjRef {
+    /// Handles are small sequential indices, spread them over the whole range (Fibonacci hashing)
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        state.write_u64((self.0 as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15));
+    }
+}
+
+struct HeapEntry {
+    object: Object,
+    marked: bool,
+    size: usize,
+}
+
+/// Owns every object created by the compiler and the VM
+/// Freed slots are reused by later allocations, so a handle is only valid while its object is alive
+pub struct Heap {
+    objects: Vec<Option<HeapEntry>>,
+    free_slots: Vec<usize>,
+    /// Weak intern table, strings only reachable from here are still collected
+    strings: HashMap<u32, Vec<ObjRef>>,
+    gray_stack: Vec<ObjRef>,
+    bytes_allocated: usize,
+    next_gc: usize,
+    stress_gc: bool,
+    collections: usize,
+    total_pause: Duration,
+    max_pause: Duration,
+    last_pause: Duration,
+}
+
+/// Snapshot of the heap returned by `VM::heap_stats`
+/// `objects` counts the live objects per `Object` variant, pauses are wall clock times of collections
+#[derive(Debug, Clone, PartialEq)]
+pub struct HeapStats {
+    pub bytes_allocated: usize,
+    pub next_gc: usize,
+    pub objects: BTreeMap<&'static str, usize>,
+    pub collections: usize,
+    pub total_pause: Duration,
+    pub max_pause: Duration,
+    pub last_pause: Duration,
+}
+
+impl Display for HeapStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "bytes allocated: {} (next collection at {})",
+            self.bytes_allocated, self.next_gc
+        )?;
+        writeln!(
+            f,
+            "collections: {} (total pause {:?}, max {:?}, last {:?})",
+            self.collections, self.total_pause, self.max_pause, self.last_pause
+        )?;
+        write!(f, "objects:")?;
+        for (kind, count) in self.objects.iter() {
+            write!(f, "\n  {:<16} {}", kind, count)?;
+        }
+        Ok(())
+    }
+}
+
+impl Default for Heap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Heap {
+    pub fn new() -> Self {
+        Heap {
+            objects: Vec::new(),
+            free_slots: Vec::new(),
+            strings: HashMap::new(),
+            gray_stack: Vec::new(),
+            bytes_allocated: 0,
+            next_gc: GC_INITIAL_THRESHOLD,
+            stress_gc: false,
+            collections: 0,
+            total_pause: Duration::ZERO,
+            max_pause: Duration::ZERO,
+            last_pause: Duration::ZERO,
+        }
+    }
+
+    /// In stress mode every allocation made by the VM triggers a full collection
+    pub fn set_stress_gc(&mut self, state: bool) {
+        self.stress_gc = state
+    }
+
+    pub fn bytes_allocated(&self) -> usize {
+        self.bytes_allocated
+    }
+
+    pub fn next_gc(&self) -> usize {
+        self.next_gc
+    }
+
+    pub fn stats(&self) -> HeapStats {
+        let mut objects = BTreeMap::new();
+        for entry in self.objects.iter().flatten() {
+            *objects.entry(entry.object.kind()).or_insert(0) += 1;
+        }
+
+        HeapStats {
+            bytes_allocated: self.bytes_allocated,
+            next_gc: self.next_gc,
+            objects,
+            collections: self.collections,
+            total_pause: self.total_pause,
+            max_pause: self.max_pause,
+            last_pause: self.last_pause,
+        }
+    }
+
+    /// Records a finished collection and how long the program was paused by it
+    pub fn record_collection(&mut self, pause: Duration) {
+        self.collections += 1;
+        self.total_pause += pause;
+        self.max_pause = self.max_pause.max(pause);
+        self.last_pause = pause;
+    }
+
+    pub fn should_collect(&self) -> bool {
+        self.stress_gc || self.bytes_allocated > self.next_gc
+    }
+
+    /// Moves the object onto the heap, this never collects
+    /// Callers that may run while a program is executing go through `VM::alloc` instead
+    pub fn alloc(&mut self, object: Object) -> ObjRef {
+        let size = object.size();
+        self.bytes_allocated += size;
+
+        let entry = Some(HeapEntry {
+            object,
+            marked: false,
+            size,
+        });
+
+        match self.free_slots.pop() {
+            Some(slot) => {
+                self.objects[slot] = entry;
+                ObjRef(slot)
+            }
+            None => {
+                self.objects.push(entry);
+                ObjRef(self.objects.len() - 1)
+            }
+        }
+    }
+
+    /// Measures the object again after it grew or shrank in place, like a list after `push`
+    /// Without this the growth would never count toward the next collection
+    pub fn resize(&mut self, obj: ObjRef) {
+        if let Some(entry) = self.objects[obj.0].as_mut() {
+            let size = entry.object.size();
+            self.bytes_allocated = self.bytes_allocated - entry.size + size;
+            entry.size = size;
+        }
+    }
+
+    /// Returns the interned copy of the string, allocating it the first time it is seen
+    pub fn intern(&mut self, value: &str) -> ObjRef {
+        match self.find_string(value) {
+            Some(interned) => interned,
+            None => self.insert_string(ObjString::new(value.to_string())),
+        }
+    }
+
+    /// Same as `intern` but takes ownership, avoiding a copy when the string is new
+    pub fn intern_owned(&mut self, value: String) -> ObjRef {
+        match self.find_string(&value) {
+            Some(interned) => interned,
+            None => self.insert_string(ObjString::new(value)),
+        }
+    }
+
+    fn find_string(&self, value: &str) -> Option<ObjRef> {
+        let hash = ObjString::hash_string(value);
+        self.strings
+            .get(&hash)?
+            .iter()
+            .copied()
+            .find(|string| self.as_str(*string) == value)
+    }
+
+    fn insert_string(&mut self, value: ObjString) -> ObjRef {
+        let hash = value.hash();
+        let interned = self.alloc(Object::ObjString(value));
+        self.strings.entry(hash).or_default().push(interned);
+        interned
+    }
+
+    pub fn get(&self, obj: ObjRef) -> &Object {
+        match self.objects.get(obj.0) {
+            Some(Some(entry)) => &entry.object,
+            _ => panic!("[Heap] dangling object handle {}", obj.0),
+        }
+    }
+
+    pub fn get_mut(&mut self, obj: ObjRef) -> &mut Object {
+        match self.objects.get_mut(obj.0) {
+            Some(Some(entry)) => &mut entry.object,
+            _ => panic!("[Heap] dangling object handle {}", obj.0),
+        }
+    }
+
+    pub fn as_str(&self, obj: ObjRef) -> &str {
+        match self.get(obj) {
+            Object::ObjString(string) => string.as_str(),
+            _ => unreachable!("[Heap] object is not a string!"),
+        }
+    }
+
+    pub fn function(&self, obj: ObjRef) -> &ObjFunction {
+        match self.get(obj) {
+            Object::ObjFunction(function) => function,
+            _ => unreachable!("[Heap] object is not a function!"),
+        }
+    }
+
+    pub fn closure(&self, obj: ObjRef) -> &ObjClosure {
+        match self.get(obj) {
+            Object::ObjClosure(closure) => closure,
+            _ => unreachable!("[Heap] object is not a closure!"),
+        }
+    }
+
+    pub fn closure_mut(&mut self, obj: ObjRef) -> &mut ObjClosure {
+        match self.get_mut(obj) {
+            Object::ObjClosure(closure) => closure,
+            _ => unreachable!("[Heap] object is not a closure!"),
+        }
+    }
+
+    pub fn upvalue(&self, obj: ObjRef) -> &ObjUpvalue {
+        match self.get(obj) {
+            Object::ObjUpvalue(upvalue) => upvalue,
+            _ => unreachable!("[Heap] object is not an upvalue!"),
+        }
+    }
+
+    pub fn upvalue_mut(&mut self, obj: ObjRef) -> &mut ObjUpvalue {
+        match self.get_mut(obj) {
+            Object::ObjUpvalue(upvalue) => upvalue,
+            _ => unreachable!("[Heap] object is not an upvalue!"),
+        }
+    }
+
+    pub fn class(&self, obj: ObjRef) -> &ObjClass {
+        match self.get(obj) {
+            Object::ObjClass(class) => class,
+            _ => unreachable!("[Heap] object is not a class!"),
+        }
+    }
+
+    pub fn class_mut(&mut self, obj: ObjRef) -> &mut ObjClass {
+        match self.get_mut(obj) {
+            Object::ObjClass(class) => class,
+            _ => unreachable!("[Heap] object is not a class!"),
+        }
+    }
+
+    pub fn list(&self, obj: ObjRef) -> &ObjList {
+        match self.get(obj) {
+            Object::ObjList(list) => list,
+            _ => unreachable!("[Heap] object is not a list!"),
+        }
+    }
+
+    pub fn list_mut(&mut self, obj: ObjRef) -> &mut ObjList {
+        match self.get_mut(obj) {
+            Object::ObjList(list) => list,
+            _ => unreachable!("[Heap] object is not a list!"),
+        }
+    }
+
+    pub fn map(&self, obj: ObjRef) -> &ObjMap {
+        match self.get(obj) {
+            Object::ObjMap(map) => map,
+            _ => unreachable!("[Heap] object is not a map!"),
+        }
+    }
+
+    pub fn map_mut(&mut self, obj: ObjRef) -> &mut ObjMap {
+        match self.get_mut(obj) {
+            Object::ObjMap(map) => map,
+            _ => unreachable!("[Heap] object is not a map!"),
+        }
+    }
+
+    /// Maps are keyed by strings, numbers, booleans and nil, other objects are not valid keys
+    pub fn is_map_key(&self, value: &ValueType) -> bool {
+        match value {
+            ValueType::Obj(obj) => matches!(self.get(*obj), Object::ObjString(_)),
+            _ => true,
+        }
+    }
+
+    pub fn instance(&self, obj: ObjRef) -> &ObjInstance {
+        match self.get(obj) {
+            Object::ObjInstance(instance) => instance,
+            _ => unreachable!("[Heap] object is not an instance!"),
+        }
+    }
+
+    pub fn instance_mut(&mut self, obj: ObjRef) -> &mut ObjInstance {
+        match self.get_mut(obj) {
+            Object::ObjInstance(instance) => instance,
+            _ => unreachable!("[Heap] object is not an instance!"),
+        }
+    }
+
+    /// Formats a value the way `print` shows it
+    pub fn value_to_string(&self, value: &ValueType) -> String {
+        self.format_value(value, &mut Vec::new())
+    }
+
+    /// `enclosing` holds the collections being formatted,
+    /// one containing itself is shown as `[...]` or `{...}`
+    fn format_value(&self, value: &ValueType, enclosing: &mut Vec<ObjRef>) -> String {
+        match value {
+            ValueType::Bool(b) => b.to_string(),
+            ValueType::Nil => "nil".to_string(),
+            ValueType::Number(n) => n.to_string(),
+            ValueType::Obj(obj) => self.object_to_string(*obj, enclosing),
+        }
+    }
+
+    fn object_to_string(&self, obj: ObjRef, enclosing: &mut Vec<ObjRef>) -> String {
+        match self.get(obj) {
+            Object::ObjString(string) => string.as_str().to_string(),
+            Object::ObjFunction(function) => match function.name {
+                Some(name) => format!("<fn {}>", self.as_str(name)),
+                None => "<script>".to_string(),
+            },
+            Object::ObjClosure(closure) => self.object_to_string(closure.function, enclosing),
+            Object::ObjUpvalue(_) => "upvalue".to_string(),
+            Object::ObjClass(class) => self.as_str(class.name).to_string(),
+            Object::ObjInstance(instance) => {
+                format!("{} instance", self.as_str(self.class(instance.class).name))
+            }
+            Object::ObjBoundMethod(bound) => self.object_to_string(bound.method, enclosing),
+            Object::ObjNative(_) => "<native fn>".to_string(),
+            Object::ObjList(_) if enclosing.contains(&obj) => "[...]".to_string(),
+            Object::ObjList(list) => {
+                enclosing.push(obj);
+                let items: Vec<String> = list
+                    .items
+                    .iter()
+                    .map(|item| self.format_value(item, enclosing))
+                    .collect();
+                enclosing.pop();
+                format!("[{}]", items.join(", "))
+            }
+            Object::ObjMap(_) if enclosing.contains(&obj) => "{...}".to_string(),
+            Object::ObjMap(map) => {
+                enclosing.push(obj);
+                let entries: Vec<String> = map
+                    .iter()
+                    .map(|(key, value)| {
+                        let key = self.format_value(key, enclosing);
+                        format!("{}: {}", key, self.format_value(value, enclosing))
+                    })
+                    .collect();
+                enclosing.pop();
+                format!("{{{}}}", entries.join(", "))
+            }
+        }
+    }
+
+    pub fn mark_value(&mut self, value: &ValueType) {
+        if let ValueType::Obj(obj) = value {
+            self.mark_object(*obj);
+        }
+    }
+
+    /// Marks the object gray, its references are traced later by `trace_references`
+    pub fn mark_object(&mut self, obj: ObjRef) {
+        if let Some(Some(entry)) = self.objects.get_mut(obj.0) {
+            if entry.marked {
+                return;
+            }
+            entry.marked = true;
+            self.gray_stack.push(obj);
+        }
+    }
+
+    /// Blackens gray objects until none is left, every object reachable from the roots is then marked
+    pub fn trace_references(&mut self) {
+        while let Some(obj) = self.gray_stack.pop() {
+            self.blacken_object(obj);
+        }
+    }
+
+    fn blacken_object(&mut self, obj: ObjRef) {
+        let mut children: Vec<ValueType> = Vec::new();
+
+        match self.get(obj) {
+            Object::ObjString(_) => (),
+            Object::ObjNative(native) => children.push(ValueType::Obj(native.name)),
+            Object::ObjBoundMethod(bound) => {
+                children.push(bound.receiver);
+                children.push(ValueType::Obj(bound.method));
+            }
+            Object::ObjClass(class) => {
+                children.push(ValueType::Obj(class.name));
+                children.extend(class.doc.map(ValueType::Obj));
+                for (name, method) in class.methods.iter() {
+                    children.push(ValueType::Obj(*name));
+                    children.push(ValueType::Obj(*method));
+                }
+            }
+            Object::ObjClosure(closure) => {
+                children.push(ValueType::Obj(closure.function));
+                children.extend(
+                    closure
+                        .upvalues
+                        .iter()
+                        .map(|upvalue| ValueType::Obj(*upvalue)),
+                );
+            }
+            Object::ObjFunction(function) => {
+                if let Some(name) = function.name {
+                    children.push(ValueType::Obj(name));
+                }
+                children.extend(function.doc.map(ValueType::Obj));
+                children.extend(function.chunk.constants.iter().copied());
+            }
+            Object::ObjInstance(instance) => {
+                children.push(ValueType::Obj(instance.class));
+                for (name, value) in instance.fields.iter() {
+                    children.push(ValueType::Obj(*name));
+                    children.push(*value);
+                }
+            }
+            Object::ObjUpvalue(upvalue) => {
+                if let Some(closed) = upvalue.closed {
+                    children.push(closed);
+                }
+            }
+            Object::ObjList(list) => children.extend(list.items.iter().copied()),
+            Object::ObjMap(map) => {
+                for (key, value) in map.iter() {
+                    children.push(*key);
+                    children.push(*value);
+                }
+            }
+        }
+
+        for child in children.iter() {
+            self.mark_value(child);
+        }
+    }
+
+    /// Drops unmarked strings from the intern table so it does not keep them alive
+    pub fn remove_white_strings(&mut self) {
+        let objects = &self.objects;
+        self.strings.retain(|_, bucket| {
+            bucket.retain(|string| matches!(&objects[string.0], Some(entry) if entry.marked));
+            !bucket.is_empty()
+        });
+    }
+
+    /// Frees every unmarked object and clears the mark of the survivors for the next cycle
+    pub fn sweep(&mut self) {
+        for (slot, entry) in self.objects.iter_mut().enumerate() {
+            match entry {
+                Some(live) if live.marked => live.marked = false,
+                Some(dead) => {
+                    self.bytes_allocated -= dead.size;
+                    *entry = None;
+                    self.free_slots.push(slot);
+                }
+                None => (),
+            }
+        }
+
+        self.next_gc = (self.bytes_allocated * GC_HEAP_GROW_FACTOR).max(GC_INITIAL_THRESHOLD);
+    }
+}
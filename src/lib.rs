@@ -1,15 +1,59 @@
 pub mod chunk;
 pub mod compiler;
 pub mod debug;
+pub mod diagnostic;
+pub mod heap;
+pub mod native;
 pub mod object;
 pub mod opcode;
 pub mod scanner;
+pub mod table;
 pub mod token;
 pub mod value;
 pub mod vm;
 
+use diagnostic::Diagnostic;
+
 pub type InterpretResult = Result<(), InterpretError>;
+#[derive(Debug)]
 pub enum InterpretError {
-    CompileError,
-    RuntimeError,
+    CompileError(Vec<Diagnostic>),
+    RuntimeError(RuntimeError),
+}
+
+/// An error raised while the VM is running, e.g. by a native function
+/// `line` is the source line of the failing instruction and `trace` holds one entry
+/// per active call frame, innermost first, like `[line 3] in fib()`
+/// Errors created outside the VM start without a location, the VM fills it in when raising them
+#[derive(Debug, Clone, PartialEq)]
+pub struct RuntimeError {
+    pub message: String,
+    pub line: usize,
+    pub trace: Vec<String>,
+}
+
+impl RuntimeError {
+    pub fn new(message: impl Into<String>) -> Self {
+        RuntimeError {
+            message: message.into(),
+            line: 0,
+            trace: Vec::new(),
+        }
+    }
+}
+
+impl std::fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)?;
+        for frame in self.trace.iter() {
+            write!(f, "\n{}", frame)?;
+        }
+        Ok(())
+    }
+}
+
+impl From<RuntimeError> for InterpretError {
+    fn from(value: RuntimeError) -> Self {
+        InterpretError::RuntimeError(value)
+    }
 }
@@ -1,33 +1,136 @@
+use lox_rc::scanner::Scanner;
+use lox_rc::token::TokenType;
 use lox_rc::vm::VM;
 use lox_rc::InterpretError;
 use std::env;
 use std::fs;
+use std::io::{self, BufRead, Write};
 use std::process;
 
 fn main() {
-    if let Some(args) = env::args().nth(1) {
-        match fs::read_to_string(&args) {
-            Ok(content) => {
-                let mut vm = VM::new();
-                if env::var("DEBUG").is_ok() {
-                    vm.set_debug(true);
-                }
+    let mut args: Vec<String> = env::args().skip(1).collect();
+
+    // `--stress-gc` collects garbage on every allocation, it can be combined with either mode
+    let stress_gc = args.iter().any(|arg| arg == "--stress-gc");
+    args.retain(|arg| arg != "--stress-gc");
+
+    match args.as_slice() {
+        [] => repl(stress_gc),
+        [flag] if flag == "--repl" => repl(stress_gc),
+        [path] if !path.starts_with("--") => run_file(path, stress_gc),
+        _ => {
+            eprintln!("Usage: lox-rc [--stress-gc] [--repl | <source_file>]");
+            process::exit(1);
+        }
+    }
+}
+
+fn new_vm(stress_gc: bool) -> VM {
+    let mut vm = VM::new();
+    if env::var("DEBUG").is_ok() {
+        vm.set_debug(true);
+    }
+    if env::var("DEBUG_GC").is_ok() {
+        vm.set_log_gc(true);
+    }
+    vm.set_stress_gc(stress_gc);
+    vm
+}
 
-                match vm.interpret(&content) {
-                    Ok(_) => (),
-                    Err(e) => match e {
-                        InterpretError::CompileError => process::exit(65),
-                        InterpretError::RuntimeError => process::exit(70),
-                    },
+fn run_file(path: &str, stress_gc: bool) {
+    match fs::read_to_string(path) {
+        Ok(content) => {
+            let mut vm = new_vm(stress_gc);
+            let result = vm.interpret(&content);
+            if env::var("DEBUG_GC").is_ok() {
+                eprintln!("{}", vm.heap_stats());
+            }
+
+            match result {
+                Ok(_) => (),
+                Err(e) => {
+                    report_error(&e);
+                    match e {
+                        InterpretError::CompileError(_) => process::exit(65),
+                        InterpretError::RuntimeError(_) => process::exit(70),
+                    }
                 }
             }
+        }
+        Err(e) => {
+            eprintln!("Error Reading Filer: [{}]", e);
+            process::exit(1);
+        }
+    }
+}
+
+/// Reads and runs one input at a time on the same VM, so globals survive between inputs
+/// Input with unclosed brackets, strings or comments keeps reading lines until they are closed
+fn repl(stress_gc: bool) {
+    let mut vm = new_vm(stress_gc);
+    vm.set_repl(true);
+
+    let stdin = io::stdin();
+    let mut input = String::new();
+
+    loop {
+        let prompt = if input.is_empty() { "> " } else { "... " };
+        print!("{}", prompt);
+        let _ = io::stdout().flush();
+
+        let mut line = String::new();
+        match stdin.lock().read_line(&mut line) {
+            Ok(0) => {
+                println!();
+                break;
+            }
+            Ok(_) => input.push_str(&line),
             Err(e) => {
-                eprintln!("Error Reading Filer: [{}]", e);
-                process::exit(1);
+                eprintln!("Error Reading Input: [{}]", e);
+                break;
+            }
+        }
+
+        if is_incomplete(&input) {
+            continue;
+        }
+
+        if !input.trim().is_empty() {
+            if let Err(e) = vm.interpret(&input) {
+                report_error(&e);
+            }
+        }
+        input.clear();
+    }
+}
+
+/// Scans the input the way the compiler will to find brackets left open,
+/// so the ones in strings and comments don't count
+/// An unterminated string or block comment is also left open so they can span lines
+fn is_incomplete(input: &str) -> bool {
+    let mut scanner = Scanner::new(input);
+    let mut depth = 0;
+
+    loop {
+        let token = scanner.scan_token();
+        match token.token_type {
+            TokenType::LEFTPAREN | TokenType::LEFTBRACE | TokenType::LEFTBRACKET => depth += 1,
+            TokenType::RIGHTPAREN | TokenType::RIGHTBRACE | TokenType::RIGHTBRACKET => depth -= 1,
+            TokenType::EOF => {
+                return depth > 0 || scanner.in_interpolation() || scanner.is_unterminated()
+            }
+            _ => (),
+        }
+    }
+}
+
+fn report_error(error: &InterpretError) {
+    match error {
+        InterpretError::CompileError(diagnostics) => {
+            for diagnostic in diagnostics.iter() {
+                eprintln!("{}", diagnostic);
             }
         }
-    } else {
-        eprintln!("Usage: jlox-rc <source_file>");
-        process::exit(1);
+        InterpretError::RuntimeError(err) => eprintln!("{}", err),
     }
 }
@@ -0,0 +1,123 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{
+    heap::ObjRef,
+    object::{ObjList, Object},
+    value::ValueType,
+    vm::{MAP_KEY_ERROR, VM},
+    RuntimeError,
+};
+
+/// Returns the number of seconds elapsed since the unix epoch
+pub fn clock(_vm: &mut VM, _args: &[ValueType]) -> Result<ValueType, RuntimeError> {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(elapsed) => Ok(ValueType::Number(elapsed.as_secs_f64())),
+        Err(e) => Err(RuntimeError::new(e.to_string())),
+    }
+}
+
+/// Number of items in a list, entries in a map or characters in a string
+pub fn len(vm: &mut VM, args: &[ValueType]) -> Result<ValueType, RuntimeError> {
+    match args[0] {
+        ValueType::Obj(obj) => match vm.heap().get(obj) {
+            Object::ObjList(list) => Ok(ValueType::Number(list.items.len() as f64)),
+            Object::ObjMap(map) => Ok(ValueType::Number(map.len() as f64)),
+            Object::ObjString(string) => {
+                Ok(ValueType::Number(string.as_str().chars().count() as f64))
+            }
+            _ => Err(RuntimeError::new(
+                "len() expects a list, a map or a string.",
+            )),
+        },
+        _ => Err(RuntimeError::new(
+            "len() expects a list, a map or a string.",
+        )),
+    }
+}
+
+/// Appends the value to the end of the list
+pub fn push(vm: &mut VM, args: &[ValueType]) -> Result<ValueType, RuntimeError> {
+    let list = list_arg(vm, &args[0], "push")?;
+    vm.heap_mut().list_mut(list).items.push(args[1]);
+    vm.resized(list);
+    Ok(ValueType::Nil)
+}
+
+/// Removes the last value of the list and returns it
+pub fn pop(vm: &mut VM, args: &[ValueType]) -> Result<ValueType, RuntimeError> {
+    let list = list_arg(vm, &args[0], "pop")?;
+    match vm.heap_mut().list_mut(list).items.pop() {
+        Some(value) => Ok(value),
+        None => Err(RuntimeError::new("Can't pop from an empty list.")),
+    }
+}
+
+/// The keys of the map as a new list, in insertion order
+pub fn keys(vm: &mut VM, args: &[ValueType]) -> Result<ValueType, RuntimeError> {
+    let map = map_arg(vm, &args[0], "keys")?;
+    let keys = vm.heap().map(map).iter().map(|(key, _)| *key).collect();
+    let list = vm.alloc(Object::ObjList(ObjList::new(keys)));
+    Ok(ValueType::Obj(list))
+}
+
+/// The values of the map as a new list, in the same order as `keys`
+pub fn values(vm: &mut VM, args: &[ValueType]) -> Result<ValueType, RuntimeError> {
+    let map = map_arg(vm, &args[0], "values")?;
+    let values = vm.heap().map(map).iter().map(|(_, value)| *value).collect();
+    let list = vm.alloc(Object::ObjList(ObjList::new(values)));
+    Ok(ValueType::Obj(list))
+}
+
+pub fn has(vm: &mut VM, args: &[ValueType]) -> Result<ValueType, RuntimeError> {
+    let map = map_arg(vm, &args[0], "has")?;
+    key_arg(vm, &args[1])?;
+    Ok(ValueType::Bool(vm.heap().map(map).contains_key(&args[1])))
+}
+
+/// Removes the key from the map and returns its value, or nil if the key was not there
+pub fn remove(vm: &mut VM, args: &[ValueType]) -> Result<ValueType, RuntimeError> {
+    let map = map_arg(vm, &args[0], "remove")?;
+    key_arg(vm, &args[1])?;
+    Ok(vm
+        .heap_mut()
+        .map_mut(map)
+        .remove(&args[1])
+        .unwrap_or(ValueType::Nil))
+}
+
+/// The doc comment of a function, method or class, or nil when it has none
+pub fn doc(vm: &mut VM, args: &[ValueType]) -> Result<ValueType, RuntimeError> {
+    let heap = vm.heap();
+    let doc = match args[0] {
+        ValueType::Obj(obj) => match heap.get(obj) {
+            Object::ObjClosure(closure) => heap.function(closure.function).doc,
+            Object::ObjBoundMethod(bound) => heap.function(heap.closure(bound.method).function).doc,
+            Object::ObjClass(class) => class.doc,
+            _ => None,
+        },
+        _ => None,
+    };
+
+    Ok(doc.map_or(ValueType::Nil, ValueType::Obj))
+}
+
+fn map_arg(vm: &VM, value: &ValueType, native: &str) -> Result<ObjRef, RuntimeError> {
+    match value {
+        ValueType::Obj(obj) if matches!(vm.heap().get(*obj), Object::ObjMap(_)) => Ok(*obj),
+        _ => Err(RuntimeError::new(format!("{}() expects a map.", native))),
+    }
+}
+
+fn key_arg(vm: &VM, value: &ValueType) -> Result<(), RuntimeError> {
+    match vm.heap().is_map_key(value) {
+        true => Ok(()),
+        false => Err(RuntimeError::new(MAP_KEY_ERROR)),
+    }
+}
+
+fn list_arg(vm: &VM, value: &ValueType, native: &str) -> Result<ObjRef, RuntimeError> {
+    match value {
+        ValueType::Obj(obj) if matches!(vm.heap().get(*obj), Object::ObjList(_)) => Ok(*obj),
+        _ => Err(RuntimeError::new(format!("{}() expects a list.", native))),
+    }
+}
@@ -1,87 +1,342 @@
+use std::collections::HashMap;
 use std::fmt::Display;
-use std::ops::Add;
+use std::hash::{Hash, Hasher};
+use std::mem;
 
 use crate::chunk::Chunk;
-use crate::InterpretError;
+use crate::heap::ObjRef;
+use crate::table::StringMap;
+use crate::value::ValueType;
+use crate::vm::VM;
+use crate::RuntimeError;
 
+/// Everything that lives on the VM heap, values refer to them through an `ObjRef` handle
 #[derive(Debug, Clone)]
 pub enum Object {
+    ObjBoundMethod(ObjBoundMethod),
+    ObjClass(ObjClass),
+    ObjClosure(ObjClosure),
     ObjFunction(ObjFunction),
+    ObjInstance(ObjInstance),
+    ObjList(ObjList),
+    ObjMap(ObjMap),
+    ObjNative(ObjNative),
     ObjString(ObjString),
+    ObjUpvalue(ObjUpvalue),
 }
 
-impl Display for Object {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl Object {
+    /// Approximate number of bytes owned by the object, used to decide when to collect
+    pub fn size(&self) -> usize {
+        let owned = match self {
+            Object::ObjString(os) => os.value.capacity(),
+            Object::ObjFunction(of) => {
+                of.chunk.op_codes_len() * (mem::size_of::<u8>() + mem::size_of::<usize>())
+                    + of.chunk.constants.len() * mem::size_of::<ValueType>()
+            }
+            Object::ObjClosure(oc) => oc.upvalues.capacity() * mem::size_of::<ObjRef>(),
+            Object::ObjClass(oc) => {
+                oc.methods.capacity() * (mem::size_of::<ObjRef>() + mem::size_of::<ObjRef>())
+            }
+            Object::ObjInstance(oi) => {
+                oi.fields.capacity() * (mem::size_of::<ObjRef>() + mem::size_of::<ValueType>())
+            }
+            Object::ObjList(ol) => ol.items.capacity() * mem::size_of::<ValueType>(),
+            Object::ObjMap(om) => {
+                om.entries.capacity() * mem::size_of::<(ValueType, ValueType)>()
+                    + om.index.capacity() * mem::size_of::<(MapKey, usize)>()
+            }
+            Object::ObjBoundMethod(_) | Object::ObjNative(_) | Object::ObjUpvalue(_) => 0,
+        };
+
+        mem::size_of::<Object>() + owned
+    }
+
+    /// Name of the variant, used when reporting heap statistics
+    pub fn kind(&self) -> &'static str {
         match self {
-            Object::ObjString(os) => write!(f, "{}", os),
-            Object::ObjFunction(of) => write!(f, "{}", of),
+            Object::ObjBoundMethod(_) => "ObjBoundMethod",
+            Object::ObjClass(_) => "ObjClass",
+            Object::ObjClosure(_) => "ObjClosure",
+            Object::ObjFunction(_) => "ObjFunction",
+            Object::ObjInstance(_) => "ObjInstance",
+            Object::ObjList(_) => "ObjList",
+            Object::ObjMap(_) => "ObjMap",
+            Object::ObjNative(_) => "ObjNative",
+            Object::ObjString(_) => "ObjString",
+            Object::ObjUpvalue(_) => "ObjUpvalue",
         }
     }
 }
 
-impl PartialEq for Object {
-    fn eq(&self, other: &Self) -> bool {
-        match (self, other) {
-            (Object::ObjString(a), Object::ObjString(b)) => a.0 == b.0,
-            _ => false,
+/// The hash is computed once on creation and reused by the intern table
+/// ObjStrings should be created through `Heap::intern` so each distinct string exists once
+#[derive(Debug, Clone)]
+pub struct ObjString {
+    value: String,
+    hash: u32,
+}
+
+impl ObjString {
+    pub fn new(source: String) -> Self {
+        let hash = Self::hash_string(&source);
+        ObjString {
+            value: source,
+            hash,
         }
     }
-}
 
-impl Add for Object {
-    type Output = Result<Self, InterpretError>;
+    pub fn as_str(&self) -> &str {
+        &self.value
+    }
 
-    fn add(self, rhs: Self) -> Self::Output {
-        match (self, rhs) {
-            (Object::ObjString(a), Object::ObjString(b)) => {
-                let mut new_str = a.0;
-                new_str.push_str(&b.0);
+    pub fn hash(&self) -> u32 {
+        self.hash
+    }
 
-                Ok(Object::ObjString(ObjString(new_str)))
-            }
-            _ => Err(InterpretError::RuntimeError),
+    /// FNV-1a
+    pub fn hash_string(source: &str) -> u32 {
+        let mut hash: u32 = 2166136261;
+        for byte in source.bytes() {
+            hash ^= byte as u32;
+            hash = hash.wrapping_mul(16777619);
         }
+        hash
     }
 }
 
-#[derive(Debug, Clone)]
-pub struct ObjString(String);
+impl PartialEq for ObjString {
+    fn eq(&self, other: &Self) -> bool {
+        self.hash == other.hash && self.value == other.value
+    }
+}
 
-impl ObjString {
-    pub fn new(source: String) -> Self {
-        ObjString(source)
+impl Eq for ObjString {}
+
+impl Hash for ObjString {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        state.write_u32(self.hash);
     }
 }
 
 impl Display for ObjString {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self.0)
+        write!(f, "{}", self.value)
     }
 }
 
 #[derive(Debug, Clone)]
 pub struct ObjFunction {
-    _arity: u32,
-    _chunk: Chunk,
-    name: Option<ObjString>,
+    pub arity: u8,
+    pub upvalue_count: usize,
+    pub chunk: Chunk,
+    pub name: Option<ObjRef>,
+    /// Text of the `///` comments before the declaration
+    pub doc: Option<ObjRef>,
 }
 
 impl ObjFunction {
     pub fn new_function() -> ObjFunction {
         ObjFunction {
-            _arity: 0,
-            _chunk: Chunk::default(),
+            arity: 0,
+            upvalue_count: 0,
+            chunk: Chunk::default(),
             name: None,
+            doc: None,
         }
     }
 }
 
-impl Display for ObjFunction {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        if let Some(fn_name) = self.name.as_ref() {
-            write!(f, "<fn {}>", fn_name)
-        } else {
-            write!(f, "<fn no_name>")
+/// The runtime representation of a function declaration,
+/// it wraps the compiled function along with the variables it captured
+#[derive(Debug, Clone)]
+pub struct ObjClosure {
+    pub function: ObjRef,
+    pub upvalues: Vec<ObjRef>,
+}
+
+impl ObjClosure {
+    pub fn new(function: ObjRef, upvalues: Vec<ObjRef>) -> Self {
+        ObjClosure { function, upvalues }
+    }
+}
+
+/// A captured variable
+/// While open, `location` is the index of the variable on the VM stack
+/// Once the variable goes out of scope the value is moved into `closed`
+#[derive(Debug, Clone)]
+pub struct ObjUpvalue {
+    pub location: usize,
+    pub closed: Option<ValueType>,
+}
+
+impl ObjUpvalue {
+    pub fn new(location: usize) -> Self {
+        ObjUpvalue {
+            location,
+            closed: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ObjClass {
+    pub name: ObjRef,
+    pub methods: StringMap<ObjRef>,
+    /// Text of the `///` comments before the declaration
+    pub doc: Option<ObjRef>,
+}
+
+impl ObjClass {
+    pub fn new(name: ObjRef) -> Self {
+        ObjClass {
+            name,
+            methods: StringMap::default(),
+            doc: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ObjInstance {
+    pub class: ObjRef,
+    pub fields: StringMap<ValueType>,
+}
+
+impl ObjInstance {
+    pub fn new(class: ObjRef) -> Self {
+        ObjInstance {
+            class,
+            fields: StringMap::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ObjList {
+    pub items: Vec<ValueType>,
+}
+
+impl ObjList {
+    pub fn new(items: Vec<ValueType>) -> Self {
+        ObjList { items }
+    }
+}
+
+/// Map keyed by strings, numbers, booleans and nil, iterating in insertion order
+/// The caller checks that keys are one of those, see `Heap::is_map_key`
+#[derive(Debug, Clone, Default)]
+pub struct ObjMap {
+    entries: Vec<(ValueType, ValueType)>,
+    /// Position of each key in `entries`
+    index: HashMap<MapKey, usize>,
+}
+
+impl ObjMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn get(&self, key: &ValueType) -> Option<ValueType> {
+        let idx = self.index.get(&MapKey::from_value(key))?;
+        Some(self.entries[*idx].1)
+    }
+
+    pub fn contains_key(&self, key: &ValueType) -> bool {
+        self.index.contains_key(&MapKey::from_value(key))
+    }
+
+    /// A new key goes last, updating an existing key keeps its position
+    pub fn insert(&mut self, key: ValueType, value: ValueType) {
+        match self.index.get(&MapKey::from_value(&key)) {
+            Some(idx) => self.entries[*idx].1 = value,
+            None => {
+                self.index
+                    .insert(MapKey::from_value(&key), self.entries.len());
+                self.entries.push((key, value));
+            }
+        }
+    }
+
+    /// The entries after the removed one move up, so the order of the rest is kept
+    pub fn remove(&mut self, key: &ValueType) -> Option<ValueType> {
+        let idx = self.index.remove(&MapKey::from_value(key))?;
+        let (_, value) = self.entries.remove(idx);
+        for (key, _) in self.entries[idx..].iter() {
+            if let Some(moved) = self.index.get_mut(&MapKey::from_value(key)) {
+                *moved -= 1;
+            }
         }
+
+        Some(value)
+    }
+
+    /// Entries in insertion order
+    pub fn iter(&self) -> impl Iterator<Item = &(ValueType, ValueType)> {
+        self.entries.iter()
     }
-}
\ No newline at end of file
+}
+
+/// Numbers are keyed by their bits, with `-0` folded into `0` since the two are equal
+/// Strings are interned so the handle identifies them
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum MapKey {
+    Bool(bool),
+    Nil,
+    Number(u64),
+    Obj(ObjRef),
+}
+
+impl MapKey {
+    fn from_value(value: &ValueType) -> Self {
+        match value {
+            ValueType::Bool(b) => MapKey::Bool(*b),
+            ValueType::Nil => MapKey::Nil,
+            ValueType::Number(n) if *n == 0.0 => MapKey::Number(0f64.to_bits()),
+            ValueType::Number(n) => MapKey::Number(n.to_bits()),
+            ValueType::Obj(obj) => MapKey::Obj(*obj),
+        }
+    }
+}
+
+/// A method accessed through an instance, it remembers the instance it was
+/// accessed from so `this` still refers to it when the method is called later
+#[derive(Debug, Clone)]
+pub struct ObjBoundMethod {
+    pub receiver: ValueType,
+    pub method: ObjRef,
+}
+
+impl ObjBoundMethod {
+    pub fn new(receiver: ValueType, method: ObjRef) -> Self {
+        ObjBoundMethod { receiver, method }
+    }
+}
+
+/// Signature of a host function callable from Lox, it receives the call arguments
+pub type NativeFn = fn(&mut VM, &[ValueType]) -> Result<ValueType, RuntimeError>;
+
+#[derive(Debug, Clone)]
+pub struct ObjNative {
+    pub name: ObjRef,
+    pub arity: u8,
+    pub function: NativeFn,
+}
+
+impl ObjNative {
+    pub fn new(name: ObjRef, arity: u8, function: NativeFn) -> Self {
+        ObjNative {
+            name,
+            arity,
+            function,
+        }
+    }
+}
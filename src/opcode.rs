@@ -1,4 +1,4 @@
-use crate::InterpretError;
+use crate::RuntimeError;
 
 #[derive(Debug, PartialEq, Clone)]
 #[repr(u8)]
@@ -27,10 +27,52 @@ pub enum OpCode {
     JumpIfFalse,
     JUMP,
     LOOP,
+    CALL,
+    CLOSURE,
+    GetUpvalue,
+    SetUpvalue,
+    CloseUpvalue,
+    CLASS,
+    GetProperty,
+    SetProperty,
+    METHOD,
+    INHERIT,
+    GetSuper,
+    ConstantLong,
+    DefineGlobalLong,
+    GetGlobalLong,
+    SetGlobalLong,
+    BuildList,
+    GetIndex,
+    SetIndex,
+    BuildMap,
+    MODULO,
+    POWER,
+    DUP,
+    DupPair,
+    STRINGIFY,
+    BitAnd,
+    BitOr,
+    BitXor,
+    BitNot,
+    ShiftLeft,
+    ShiftRight,
+    ClassDoc,
+    DefineConstGlobal,
+    DefineConstGlobalLong,
+    ClosureLong,
+    ClassLong,
+    ClassDocLong,
+    MethodLong,
+    GetPropertyLong,
+    SetPropertyLong,
+    GetSuperLong,
+    IntDivide,
+    SINK,
 }
 
 impl TryFrom<u8> for OpCode {
-    type Error = InterpretError;
+    type Error = RuntimeError;
     fn try_from(value: u8) -> Result<Self, Self::Error> {
         match value {
             0 => Ok(OpCode::CONSTANT),
@@ -57,7 +99,70 @@ impl TryFrom<u8> for OpCode {
             21 => Ok(OpCode::JumpIfFalse),
             22 => Ok(OpCode::JUMP),
             23 => Ok(OpCode::LOOP),
-            _ => Err(InterpretError::CompileError),
+            24 => Ok(OpCode::CALL),
+            25 => Ok(OpCode::CLOSURE),
+            26 => Ok(OpCode::GetUpvalue),
+            27 => Ok(OpCode::SetUpvalue),
+            28 => Ok(OpCode::CloseUpvalue),
+            29 => Ok(OpCode::CLASS),
+            30 => Ok(OpCode::GetProperty),
+            31 => Ok(OpCode::SetProperty),
+            32 => Ok(OpCode::METHOD),
+            33 => Ok(OpCode::INHERIT),
+            34 => Ok(OpCode::GetSuper),
+            35 => Ok(OpCode::ConstantLong),
+            36 => Ok(OpCode::DefineGlobalLong),
+            37 => Ok(OpCode::GetGlobalLong),
+            38 => Ok(OpCode::SetGlobalLong),
+            39 => Ok(OpCode::BuildList),
+            40 => Ok(OpCode::GetIndex),
+            41 => Ok(OpCode::SetIndex),
+            42 => Ok(OpCode::BuildMap),
+            43 => Ok(OpCode::MODULO),
+            44 => Ok(OpCode::POWER),
+            45 => Ok(OpCode::DUP),
+            46 => Ok(OpCode::DupPair),
+            47 => Ok(OpCode::STRINGIFY),
+            48 => Ok(OpCode::BitAnd),
+            49 => Ok(OpCode::BitOr),
+            50 => Ok(OpCode::BitXor),
+            51 => Ok(OpCode::BitNot),
+            52 => Ok(OpCode::ShiftLeft),
+            53 => Ok(OpCode::ShiftRight),
+            54 => Ok(OpCode::ClassDoc),
+            55 => Ok(OpCode::DefineConstGlobal),
+            56 => Ok(OpCode::DefineConstGlobalLong),
+            57 => Ok(OpCode::ClosureLong),
+            58 => Ok(OpCode::ClassLong),
+            59 => Ok(OpCode::ClassDocLong),
+            60 => Ok(OpCode::MethodLong),
+            61 => Ok(OpCode::GetPropertyLong),
+            62 => Ok(OpCode::SetPropertyLong),
+            63 => Ok(OpCode::GetSuperLong),
+            64 => Ok(OpCode::IntDivide),
+            65 => Ok(OpCode::SINK),
+            _ => Err(RuntimeError::new(format!("Unknown opcode {}.", value))),
+        }
+    }
+}
+
+impl OpCode {
+    /// The variant taking a 24-bit constant index, for instructions that have one
+    pub fn long_form(&self) -> Option<OpCode> {
+        match self {
+            OpCode::CONSTANT => Some(OpCode::ConstantLong),
+            OpCode::DefineGlobal => Some(OpCode::DefineGlobalLong),
+            OpCode::DefineConstGlobal => Some(OpCode::DefineConstGlobalLong),
+            OpCode::GetGlobal => Some(OpCode::GetGlobalLong),
+            OpCode::SetGlobal => Some(OpCode::SetGlobalLong),
+            OpCode::CLOSURE => Some(OpCode::ClosureLong),
+            OpCode::CLASS => Some(OpCode::ClassLong),
+            OpCode::ClassDoc => Some(OpCode::ClassDocLong),
+            OpCode::METHOD => Some(OpCode::MethodLong),
+            OpCode::GetProperty => Some(OpCode::GetPropertyLong),
+            OpCode::SetProperty => Some(OpCode::SetPropertyLong),
+            OpCode::GetSuper => Some(OpCode::GetSuperLong),
+            _ => None,
         }
     }
 }
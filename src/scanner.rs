@@ -1,39 +1,57 @@
 use crate::token::{Token, TokenType};
-use core::str;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub struct Scanner<'a> {
     line: usize,
+    /// Characters consumed so far on the current line
+    line_chars: usize,
+    /// Column of the token being scanned in characters, starting at 1
+    column: usize,
+    /// Byte offsets into `source`, always on a character boundary
     start: usize,
     current: usize,
-    source: &'a [u8],
+    source: &'a str,
+    /// One entry per `${` being scanned, counting the braces opened inside it
+    /// so the `}` that resumes the string can be told apart
+    interpolations: Vec<usize>,
+    /// Set when the last token was an INTERPOLATION, a `}` right after it means `${}` is empty
+    empty_interpolation: bool,
+    /// Set when the source ended inside a string or a block comment
+    unterminated: bool,
 }
 
 impl<'a> Scanner<'a> {
     pub fn new(source: &'a str) -> Self {
         Scanner {
             line: 1,
+            line_chars: 0,
+            column: 1,
             start: 0,
             current: 0,
-            source: source.as_bytes(),
+            source,
+            interpolations: Vec::new(),
+            empty_interpolation: false,
+            unterminated: false,
         }
     }
 
-    pub fn get_slice_constant(&self, start: usize, end: usize) -> Option<f64> {
-        if end <= self.source.len() {
-            if let Ok(str_value) = std::str::from_utf8(&self.source[start..=end]) {
-                str_value.parse::<f64>().ok()
-            } else {
-                None
-            }
-        } else {
-            None
-        }
+    /// Whether the source ended inside a `${...}` of a string
+    pub fn in_interpolation(&self) -> bool {
+        !self.interpolations.is_empty()
+    }
+
+    /// Whether the source ended inside a string or a block comment
+    pub fn is_unterminated(&self) -> bool {
+        self.unterminated
     }
 
     pub fn scan_token(&mut self) -> Token {
-        self.skip_whitespace();
+        let empty_interpolation = std::mem::take(&mut self.empty_interpolation);
+        if let Err(error) = self.skip_whitespace() {
+            return error;
+        }
         self.start = self.current;
+        self.column = self.line_chars + 1;
 
         if self.is_at_end() {
             return self.make_token(TokenType::EOF);
@@ -44,15 +62,79 @@ impl<'a> Scanner<'a> {
         match character {
             '(' => self.make_token(TokenType::LEFTPAREN),
             ')' => self.make_token(TokenType::RIGHTPAREN),
-            '{' => self.make_token(TokenType::LEFTBRACE),
-            '}' => self.make_token(TokenType::RIGHTBRACE),
+            '{' => {
+                if let Some(depth) = self.interpolations.last_mut() {
+                    *depth += 1;
+                }
+                self.make_token(TokenType::LEFTBRACE)
+            }
+            '}' => match self.interpolations.last_mut() {
+                Some(0) => {
+                    self.interpolations.pop();
+                    let error =
+                        empty_interpolation.then(|| "Expect expression inside '${}'.".to_string());
+                    self.match_string(error)
+                }
+                Some(depth) => {
+                    *depth -= 1;
+                    self.make_token(TokenType::RIGHTBRACE)
+                }
+                None => self.make_token(TokenType::RIGHTBRACE),
+            },
             ';' => self.make_token(TokenType::SEMICOLON),
             ',' => self.make_token(TokenType::COMMA),
+            ':' => self.make_token(TokenType::COLON),
+            '?' => self.make_token(TokenType::QUESTION),
+            '[' => self.make_token(TokenType::LEFTBRACKET),
+            ']' => self.make_token(TokenType::RIGHTBRACKET),
             '.' => self.make_token(TokenType::DOT),
-            '-' => self.make_token(TokenType::MINUS),
-            '+' => self.make_token(TokenType::PLUS),
-            '/' => self.make_token(TokenType::SLASH),
-            '*' => self.make_token(TokenType::STAR),
+            '-' => {
+                if self.match_token('=') {
+                    return self.make_token(TokenType::MINUSEQUAL);
+                }
+                if self.match_token('-') {
+                    return self.make_token(TokenType::MINUSMINUS);
+                }
+                self.make_token(TokenType::MINUS)
+            }
+            '+' => {
+                if self.match_token('=') {
+                    return self.make_token(TokenType::PLUSEQUAL);
+                }
+                if self.match_token('+') {
+                    return self.make_token(TokenType::PLUSPLUS);
+                }
+                self.make_token(TokenType::PLUS)
+            }
+            '/' => {
+                if self.match_token('=') {
+                    return self.make_token(TokenType::SLASHEQUAL);
+                }
+                // Other comments are skipped before getting here, only `///` is left
+                if self.match_token('/') {
+                    return self.doc_comment();
+                }
+                self.make_token(TokenType::SLASH)
+            }
+            '%' => self.make_token(TokenType::PERCENT),
+            '&' => self.make_token(TokenType::AMPERSAND),
+            '|' => self.make_token(TokenType::PIPE),
+            '^' => self.make_token(TokenType::CARET),
+            '~' => {
+                if self.match_token('/') {
+                    return self.make_token(TokenType::TILDESLASH);
+                }
+                self.make_token(TokenType::TILDE)
+            }
+            '*' => {
+                if self.match_token('*') {
+                    return self.make_token(TokenType::STARSTAR);
+                }
+                if self.match_token('=') {
+                    return self.make_token(TokenType::STAREQUAL);
+                }
+                self.make_token(TokenType::STAR)
+            }
             '!' => {
                 if self.match_token('=') {
                     return self.make_token(TokenType::BANGEQUAL);
@@ -63,27 +145,33 @@ impl<'a> Scanner<'a> {
                 if self.match_token('=') {
                     return self.make_token(TokenType::EQUALEQUAL);
                 }
+                if self.match_token('>') {
+                    return self.make_token(TokenType::ARROW);
+                }
                 self.make_token(TokenType::EQUAL)
             }
             '<' => {
                 if self.match_token('=') {
                     return self.make_token(TokenType::LESSEQUAL);
                 }
+                if self.match_token('<') {
+                    return self.make_token(TokenType::LESSLESS);
+                }
                 self.make_token(TokenType::LESS)
             }
             '>' => {
                 if self.match_token('=') {
                     return self.make_token(TokenType::GREATEREQUAL);
                 }
-                self.make_token(TokenType::GREATEREQUAL)
+                if self.match_token('>') {
+                    return self.make_token(TokenType::GREATERGREATER);
+                }
+                self.make_token(TokenType::GREATER)
             }
-            '"' => self.match_string(),
+            '"' => self.match_string(None),
             '0'..='9' => self.match_number(),
-            'a'..='z' | 'A'..='Z' | '_' => {
-                while self.peek().is_ascii_alphabetic()
-                    || self.peek().is_ascii_digit()
-                    || self.peek() == '_'
-                {
+            c if c.is_alphabetic() || c == '_' => {
+                while self.peek().is_alphanumeric() || self.peek() == '_' {
                     self.advance();
                 }
                 self.identifier_type()
@@ -92,7 +180,9 @@ impl<'a> Scanner<'a> {
         }
     }
 
-    fn skip_whitespace(&mut self) {
+    /// Skips whitespace and comments, except `///` doc comments which become tokens
+    /// Fails with an error token when a block comment is never closed
+    fn skip_whitespace(&mut self) -> Result<(), Token> {
         loop {
             let character = self.peek();
             match character {
@@ -100,30 +190,109 @@ impl<'a> Scanner<'a> {
                     self.advance();
                 }
                 '\n' => {
-                    self.line += 1;
                     self.advance();
+                    self.new_line();
                 }
-                '/' => {
-                    if self.peek_next() == '/' {
+                '/' => match self.peek_next() {
+                    '/' => {
+                        if self.is_doc_comment() {
+                            return Ok(());
+                        }
                         while self.peek() != '\n' && !self.is_at_end() {
                             self.advance();
                         }
                     }
-                    return;
+                    '*' => self.block_comment()?,
+                    _ => return Ok(()),
+                },
+                _ => return Ok(()),
+            }
+        }
+    }
+
+    /// `///` starts a doc comment, but `////` and longer are plain comments
+    fn is_doc_comment(&self) -> bool {
+        self.char_at(self.current + 2) == '/' && self.char_at(self.current + 3) != '/'
+    }
+
+    /// Skips a `/* */` comment, a `/*` inside it needs its own `*/`
+    fn block_comment(&mut self) -> Result<(), Token> {
+        let (line, column) = (self.line, self.line_chars + 1);
+        self.advance();
+        self.advance();
+
+        let mut depth = 1;
+        while depth > 0 {
+            if self.is_at_end() {
+                self.unterminated = true;
+                return Err(Token::new(
+                    TokenType::ERROR,
+                    "Unterminated block comment".to_string(),
+                    line,
+                    column,
+                ));
+            }
+
+            match self.advance() {
+                '/' if self.peek() == '*' => {
+                    self.advance();
+                    depth += 1;
                 }
-                _ => return,
+                '*' if self.peek() == '/' => {
+                    self.advance();
+                    depth -= 1;
+                }
+                '\n' => self.new_line(),
+                _ => (),
             }
         }
+
+        Ok(())
+    }
+
+    /// Called after consuming `//`, the lexeme is the rest of the line
+    /// without the third '/' and the space usually following it
+    fn doc_comment(&mut self) -> Token {
+        self.advance();
+        self.match_token(' ');
+
+        let text_start = self.current;
+        while self.peek() != '\n' && !self.is_at_end() {
+            self.advance();
+        }
+        let text = self.source[text_start..self.current].trim_end_matches('\r');
+
+        Token::new(
+            TokenType::DOCCOMMENT,
+            text.to_string(),
+            self.line,
+            self.column,
+        )
     }
 
     fn identifier_type(&mut self) -> Token {
-        match self.source[self.start] as char {
+        match self.char_at(self.start) {
             'a' => self.check_keyword(1, 2, "nd", TokenType::AND),
-            'c' => self.check_keyword(1, 4, "lass", TokenType::CLASS),
+            'b' => self.check_keyword(1, 4, "reak", TokenType::BREAK),
+            'c' => {
+                if self.current - self.start > 1 {
+                    match self.char_at(self.start + 1) {
+                        'l' => self.check_keyword(2, 3, "ass", TokenType::CLASS),
+                        // "const" and "continue" only differ from the fourth character
+                        'o' => match self.char_at(self.start + 3) {
+                            's' => self.check_keyword(2, 3, "nst", TokenType::CONST),
+                            _ => self.check_keyword(2, 6, "ntinue", TokenType::CONTINUE),
+                        },
+                        _ => self.make_token(TokenType::IDENTIFIER),
+                    }
+                } else {
+                    self.make_token(TokenType::IDENTIFIER)
+                }
+            }
             'e' => self.check_keyword(1, 3, "lse", TokenType::ELSE),
             'f' => {
                 if self.current - self.start > 1 {
-                    match self.source[self.start + 1] as char {
+                    match self.char_at(self.start + 1) {
                         'a' => self.check_keyword(2, 3, "lse", TokenType::FALSE),
                         'o' => self.check_keyword(2, 1, "r", TokenType::FOR),
                         'u' => self.check_keyword(2, 1, "n", TokenType::FUN),
@@ -141,7 +310,7 @@ impl<'a> Scanner<'a> {
             's' => self.check_keyword(1, 4, "uper", TokenType::SUPER),
             't' => {
                 if self.current - self.start > 1 {
-                    match self.source[self.start + 1] as char {
+                    match self.char_at(self.start + 1) {
                         'h' => self.check_keyword(2, 2, "is", TokenType::THIS),
                         'r' => self.check_keyword(2, 2, "ue", TokenType::TRUE),
                         _ => self.make_token(TokenType::IDENTIFIER),
@@ -163,77 +332,247 @@ impl<'a> Scanner<'a> {
         rest: &str,
         token_type: TokenType,
     ) -> Token {
-        let slice = &self.source[self.start + start..self.start + start + length];
-        if let Ok(slice_str) = std::str::from_utf8(slice) {
-            if self.current - self.start == start + length && slice_str == rest {
-                return self.make_token(token_type);
-            }
+        if self.current - self.start == start + length
+            && self.source.get(self.start + start..self.current) == Some(rest)
+        {
+            return self.make_token(token_type);
         }
         self.make_token(TokenType::IDENTIFIER)
     }
 
+    /// Decimal literals like `1_000`, `2.5` and `1e-9`, or integers written
+    /// in hex, binary or octal like `0x1F`, `0b1010` and `0o17`
+    /// The lexeme is left as written, `Compiler::parse_number` computes the value
     fn match_number(&mut self) -> Token {
-        while self.is_digit(self.peek()) {
-            self.advance();
+        if self.char_at(self.start) == '0' {
+            let radix = match self.peek() {
+                'x' | 'X' => Some((16, "hex")),
+                'b' | 'B' => Some((2, "binary")),
+                'o' | 'O' => Some((8, "octal")),
+                _ => None,
+            };
+            if let Some((radix, name)) = radix {
+                self.advance();
+                return self.match_radix_number(radix, name);
+            }
+        }
+
+        // The first digit is already consumed
+        if let Err(msg) = self.digits(10, true) {
+            return self.number_error(msg);
         }
 
-        if self.peek() == '.' && self.is_digit(self.peek_next()) {
+        if self.peek() == '.' && self.peek_next().is_ascii_digit() {
             self.advance();
+            if let Err(msg) = self.digits(10, false) {
+                return self.number_error(msg);
+            }
+        }
 
-            while self.is_digit(self.peek()) {
+        if matches!(self.peek(), 'e' | 'E') {
+            self.advance();
+            if matches!(self.peek(), '+' | '-') {
                 self.advance();
             }
+            if !self.peek().is_ascii_digit() {
+                return self.number_error("Expect digits in exponent.".to_string());
+            }
+            if let Err(msg) = self.digits(10, false) {
+                return self.number_error(msg);
+            }
         }
 
         self.make_token(TokenType::NUMBER)
     }
 
-    fn is_digit(&self, c: char) -> bool {
-        c.is_ascii_digit()
+    /// Called after consuming the `0x`, `0b` or `0o` prefix
+    fn match_radix_number(&mut self, radix: u32, name: &str) -> Token {
+        let prefix = self.source[self.start..self.current].to_string();
+        match self.digits(radix, false) {
+            Ok(0) => {
+                return self.number_error(format!("Expect {} digits after '{}'.", name, prefix))
+            }
+            Ok(_) => {}
+            Err(msg) => return self.number_error(msg),
+        }
+
+        let next = self.peek();
+        if next.is_alphanumeric() {
+            return self.number_error(format!("Invalid digit '{}' in {} literal.", next, name));
+        }
+
+        self.make_token(TokenType::NUMBER)
     }
 
-    fn match_string(&mut self) -> Token {
-        while self.peek() != '"' && !self.is_at_end() {
-            if self.peek() == '\n' {
-                self.line += 1;
+    /// Consumes digits of the given radix, allowing `_` between two digits
+    /// `after_digit` tells whether the character before was a digit
+    /// Returns how many digits were consumed
+    fn digits(&mut self, radix: u32, mut after_digit: bool) -> Result<usize, String> {
+        let mut count = 0;
+        loop {
+            let character = self.peek();
+            if character == '_' {
+                if !after_digit || !self.peek_next().is_digit(radix) {
+                    return Err("Digit separator '_' must be between digits.".to_string());
+                }
+                after_digit = false;
+            } else if character.is_digit(radix) {
+                after_digit = true;
+                count += 1;
+            } else {
+                return Ok(count);
             }
             self.advance();
         }
+    }
+
+    /// Skips the rest of a malformed literal so it isn't scanned again as other tokens
+    fn number_error(&mut self, msg: String) -> Token {
+        while self.peek().is_alphanumeric() || self.peek() == '_' {
+            self.advance();
+        }
+        self.error_token(msg)
+    }
+
+    /// Scans a string literal, or the rest of one after an interpolated expression
+    /// The lexeme holds the string with its escape sequences already processed
+    /// A `${` ends the token as an INTERPOLATION, the expression is scanned as
+    /// regular tokens until the matching `}` resumes the string
+    /// An error found before the string resumed is reported once the whole string is consumed
+    fn match_string(&mut self, mut error: Option<String>) -> Token {
+        let mut value = String::new();
+
+        loop {
+            if self.is_at_end() {
+                self.unterminated = true;
+                return self.error_token("Unterminated string".to_string());
+            }
+
+            match self.advance() {
+                '"' => break,
+                '\n' => {
+                    value.push('\n');
+                    self.new_line();
+                }
+                '$' if self.peek() == '{' => {
+                    self.advance();
+                    self.interpolations.push(0);
+                    self.empty_interpolation = error.is_none();
+                    return self.string_token(TokenType::INTERPOLATION, value, error);
+                }
+                '\\' => match self.escape_sequence() {
+                    Ok(character) => value.push(character),
+                    // Keep going so the rest of the string isn't scanned as code
+                    Err(msg) => {
+                        error.get_or_insert(msg);
+                    }
+                },
+                character => value.push(character),
+            }
+        }
+
+        self.string_token(TokenType::STRING, value, error)
+    }
 
+    /// Called after consuming a '\\', returns the character the escape stands for
+    fn escape_sequence(&mut self) -> Result<char, String> {
         if self.is_at_end() {
-            return self.error_token("Unterminated string".to_string());
+            return Err("Unterminated string".to_string());
         }
 
-        self.advance();
-        self.make_token(TokenType::STRING)
+        match self.advance() {
+            'n' => Ok('\n'),
+            't' => Ok('\t'),
+            'r' => Ok('\r'),
+            '0' => Ok('\0'),
+            '"' => Ok('"'),
+            '\\' => Ok('\\'),
+            '$' => Ok('$'),
+            'u' => self.unicode_escape(),
+            '\n' => {
+                self.new_line();
+                Err("Invalid escape sequence '\\' at end of line.".to_string())
+            }
+            other => Err(format!("Invalid escape sequence '\\{}'.", other)),
+        }
+    }
+
+    /// `\u{XXXX}` with one to six hex digits naming a unicode scalar value
+    fn unicode_escape(&mut self) -> Result<char, String> {
+        if !self.match_token('{') {
+            return Err("Expect '{' after '\\u'.".to_string());
+        }
+
+        let digits_start = self.current;
+        while self.peek().is_ascii_hexdigit() {
+            self.advance();
+        }
+        let digits = &self.source[digits_start..self.current];
+
+        if !self.match_token('}') {
+            return Err("Expect '}' after unicode escape digits.".to_string());
+        }
+        if digits.is_empty() || digits.len() > 6 {
+            return Err("Unicode escape must have between 1 and 6 hex digits.".to_string());
+        }
+
+        u32::from_str_radix(digits, 16)
+            .ok()
+            .and_then(char::from_u32)
+            .ok_or_else(|| format!("Invalid unicode escape '\\u{{{}}}'.", digits))
+    }
+
+    fn string_token(
+        &mut self,
+        token_type: TokenType,
+        value: String,
+        error: Option<String>,
+    ) -> Token {
+        match error {
+            Some(msg) => self.error_token(msg),
+            None => Token::new(token_type, value, self.line, self.column),
+        }
+    }
+
+    /// Called after consuming a '\n', columns restart from the next character
+    fn new_line(&mut self) {
+        self.line += 1;
+        self.line_chars = 0;
     }
 
     fn match_token(&mut self, expected: char) -> bool {
-        if self.is_at_end() || self.source[self.current] as char != expected {
+        if self.is_at_end() || self.peek() != expected {
             return false;
         }
-        self.current += 1;
+        self.advance();
 
         true
     }
 
+    /// The character starting at byte `offset`, or '\0' past the end
+    fn char_at(&self, offset: usize) -> char {
+        self.source
+            .get(offset..)
+            .and_then(|rest| rest.chars().next())
+            .unwrap_or('\0')
+    }
+
     fn peek_next(&self) -> char {
         if self.is_at_end() {
             return '\0';
         }
-        self.source[self.current + 1].into()
+        self.char_at(self.current + self.peek().len_utf8())
     }
 
     fn peek(&self) -> char {
-        if self.is_at_end() {
-            return '\0';
-        }
-        self.source[self.current].into()
+        self.char_at(self.current)
     }
 
     fn advance(&mut self) -> char {
-        self.current += 1;
-        self.source[self.current - 1].into()
+        let character = self.peek();
+        self.current += character.len_utf8();
+        self.line_chars += 1;
+        character
     }
 
     fn make_token(&mut self, token_type: TokenType) -> Token {
@@ -241,20 +580,12 @@ impl<'a> Scanner<'a> {
             self.start <= self.current,
             "Invalid slice bounds in Scanner"
         );
-        let (start, end) = match token_type {
-            TokenType::STRING => (self.start + 1, self.current - 1),
-            _ => (self.start, self.current),
-        };
-
-        let lexeme = match str::from_utf8(&self.source[start..end]) {
-            Ok(s) => s.to_string(),
-            Err(e) => return self.error_token(e.to_string()),
-        };
-        Token::new(token_type, lexeme, self.line)
+        let lexeme = self.source[self.start..self.current].to_string();
+        Token::new(token_type, lexeme, self.line, self.column)
     }
 
     fn error_token(&mut self, err_msg: String) -> Token {
-        Token::new(TokenType::ERROR, err_msg, self.line)
+        Token::new(TokenType::ERROR, err_msg, self.line, self.column)
     }
 
     fn is_at_end(&self) -> bool {
@@ -0,0 +1,32 @@
+use std::collections::{HashMap, HashSet};
+use std::hash::{BuildHasherDefault, Hasher};
+
+use crate::heap::ObjRef;
+
+/// Hasher for keys that already carry a well distributed hash, like ObjRef
+/// The hash is passed through instead of being hashed again
+#[derive(Default)]
+pub struct PrehashedHasher(u64);
+
+impl Hasher for PrehashedHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 = (self.0 << 8) | *byte as u64;
+        }
+    }
+
+    fn write_u64(&mut self, n: u64) {
+        self.0 = n;
+    }
+}
+
+/// Hash table keyed by interned strings, used for globals, fields and methods
+/// Interned strings are unique so the handle alone identifies the string
+pub type StringMap<V> = HashMap<ObjRef, V, BuildHasherDefault<PrehashedHasher>>;
+
+/// Set of interned strings, like the names of constant globals
+pub type StringSet = HashSet<ObjRef, BuildHasherDefault<PrehashedHasher>>;
@@ -3,13 +3,15 @@ pub struct Token {
     pub token_type: TokenType,
     pub lexeme: String,
     pub line: usize,
+    pub column: usize,
 }
 
 impl Token {
-    pub fn new(token_type: TokenType, lexeme: String, line: usize) -> Self {
+    pub fn new(token_type: TokenType, lexeme: String, line: usize, column: usize) -> Self {
         Token {
             token_type,
             line,
+            column,
             lexeme,
         }
     }
@@ -30,6 +32,10 @@ pub enum TokenType {
     RIGHTPAREN,
     LEFTBRACE,
     RIGHTBRACE,
+    LEFTBRACKET,
+    RIGHTBRACKET,
+    COLON,
+    QUESTION,
     COMMA,
     DOT,
     MINUS,
@@ -37,22 +43,43 @@ pub enum TokenType {
     SEMICOLON,
     SLASH,
     STAR,
+    PERCENT,
+    AMPERSAND,
+    PIPE,
+    CARET,
+    TILDE,
     // One or two character tokens.
     BANG,
     BANGEQUAL,
     EQUAL,
     EQUALEQUAL,
+    ARROW,
     GREATER,
     GREATEREQUAL,
     LESS,
     LESSEQUAL,
+    MINUSEQUAL,
+    MINUSMINUS,
+    PLUSEQUAL,
+    PLUSPLUS,
+    SLASHEQUAL,
+    STAREQUAL,
+    STARSTAR,
+    TILDESLASH,
+    LESSLESS,
+    GREATERGREATER,
     // Literals.
     IDENTIFIER,
     STRING,
+    INTERPOLATION,
     NUMBER,
+    DOCCOMMENT,
     // Keywords.
     AND,
+    BREAK,
     CLASS,
+    CONST,
+    CONTINUE,
     ELSE,
     FALSE,
     FOR,
@@ -1,27 +1,13 @@
-use std::fmt;
+use crate::heap::ObjRef;
+use crate::RuntimeError;
 
-use crate::object::Object;
-use crate::InterpretError;
-
-#[derive(Debug, Clone)]
+/// Objects are referenced by handle, their contents live on the VM heap
+#[derive(Debug, Clone, Copy)]
 pub enum ValueType {
     Bool(bool),
     Nil,
     Number(f64),
-    Obj(Object),
-}
-
-impl fmt::Display for ValueType {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
-            ValueType::Bool(b) => write!(f, "{}", b),
-            ValueType::Nil => write!(f, "nil"),
-            ValueType::Number(n) => write!(f, "{}", n),
-            ValueType::Obj(o) => {
-                write!(f, "{}", o)
-            }
-        }
-    }
+    Obj(ObjRef),
 }
 
 impl ValueType {
@@ -36,6 +22,48 @@ impl ValueType {
     pub fn is_obj_type(&self) -> bool {
         matches!(self, ValueType::Obj(_))
     }
+
+    /// The number as a 64-bit integer, when it holds one exactly
+    pub fn as_integer(&self) -> Option<i64> {
+        match self {
+            // i64::MAX isn't representable, 2^63 is the first value out of range
+            ValueType::Number(n)
+                if n.fract() == 0.0 && *n >= -(2f64.powi(63)) && *n < 2f64.powi(63) =>
+            {
+                Some(*n as i64)
+            }
+            _ => None,
+        }
+    }
+
+    /// `~`, `Not` is already taken by `!`
+    pub fn bit_not(self) -> Result<Self, RuntimeError> {
+        match self.as_integer() {
+            Some(a) => integer_result(!a),
+            None => Err(RuntimeError::new("Operand must be an integer.")),
+        }
+    }
+
+    /// `~/`, the quotient rounded toward zero so that `(a ~/ b) * b + a % b == a`
+    pub fn int_div(self, rhs: Self) -> Result<Self, RuntimeError> {
+        match (self, rhs) {
+            (ValueType::Number(a), ValueType::Number(b)) => {
+                if b == 0.0 {
+                    return Err(RuntimeError::new("Integer division by zero."));
+                }
+                Ok(ValueType::Number((a / b).trunc()))
+            }
+            _ => Err(RuntimeError::new("Operands must be numbers.")),
+        }
+    }
+
+    /// `**`, there is no operator trait for it
+    pub fn pow(self, rhs: Self) -> Result<Self, RuntimeError> {
+        match (self, rhs) {
+            (ValueType::Number(a), ValueType::Number(b)) => Ok(ValueType::Number(a.powf(b))),
+            _ => Err(RuntimeError::new("Operands must be numbers.")),
+        }
+    }
 }
 
 // This is need to use the ==
@@ -62,49 +90,142 @@ impl PartialOrd for ValueType {
     }
 }
 
-use std::ops::{Add, Div, Mul, Sub};
+use std::ops::{Add, BitAnd, BitOr, BitXor, Div, Mul, Rem, Shl, Shr, Sub};
 
 impl Add for ValueType {
-    type Output = Result<Self, InterpretError>;
+    type Output = Result<Self, RuntimeError>;
 
     fn add(self, rhs: Self) -> Self::Output {
         match (self, rhs) {
             (ValueType::Number(a), ValueType::Number(b)) => Ok(ValueType::Number(a + b)),
-            (ValueType::Obj(a), ValueType::Obj(b)) => Ok(ValueType::Obj((a + b)?)),
-            _ => Err(InterpretError::RuntimeError),
+            _ => Err(RuntimeError::new(
+                "Operands must be two numbers or two strings.",
+            )),
         }
     }
 }
 
 impl Sub for ValueType {
-    type Output = Result<Self, InterpretError>;
+    type Output = Result<Self, RuntimeError>;
 
     fn sub(self, rhs: Self) -> Self::Output {
         match (self, rhs) {
             (ValueType::Number(a), ValueType::Number(b)) => Ok(ValueType::Number(a - b)),
-            _ => Err(InterpretError::RuntimeError),
+            _ => Err(RuntimeError::new("Operands must be numbers.")),
         }
     }
 }
 
 impl Mul for ValueType {
-    type Output = Result<Self, InterpretError>;
+    type Output = Result<Self, RuntimeError>;
 
     fn mul(self, rhs: Self) -> Self::Output {
         match (self, rhs) {
             (ValueType::Number(a), ValueType::Number(b)) => Ok(ValueType::Number(a * b)),
-            _ => Err(InterpretError::RuntimeError),
+            _ => Err(RuntimeError::new("Operands must be numbers.")),
         }
     }
 }
 
 impl Div for ValueType {
-    type Output = Result<Self, InterpretError>;
+    type Output = Result<Self, RuntimeError>;
 
     fn div(self, rhs: Self) -> Self::Output {
         match (self, rhs) {
             (ValueType::Number(a), ValueType::Number(b)) => Ok(ValueType::Number(a / b)),
-            _ => Err(InterpretError::RuntimeError),
+            _ => Err(RuntimeError::new("Operands must be numbers.")),
+        }
+    }
+}
+
+/// Remainder of the division, with the sign of the dividend like C's `fmod`
+impl Rem for ValueType {
+    type Output = Result<Self, RuntimeError>;
+
+    fn rem(self, rhs: Self) -> Self::Output {
+        match (self, rhs) {
+            (ValueType::Number(a), ValueType::Number(b)) => Ok(ValueType::Number(a % b)),
+            _ => Err(RuntimeError::new("Operands must be numbers.")),
         }
     }
 }
+
+/// Both operands as 64-bit integers, for the bitwise operators
+fn integer_operands(a: ValueType, b: ValueType) -> Result<(i64, i64), RuntimeError> {
+    match (a.as_integer(), b.as_integer()) {
+        (Some(a), Some(b)) => Ok((a, b)),
+        _ => Err(RuntimeError::new("Operands must be integers.")),
+    }
+}
+
+/// Whether an integer of this magnitude converts to a number without losing bits,
+/// its significant bits have to fit in the 53 bits of an f64's mantissa
+pub fn is_exact_integer(magnitude: u128) -> bool {
+    magnitude == 0 || magnitude >> magnitude.trailing_zeros() < 1 << 53
+}
+
+/// The result of a bitwise operator as a number, reported if it would be rounded
+fn integer_result(value: i64) -> Result<ValueType, RuntimeError> {
+    if is_exact_integer(value.unsigned_abs() as u128) {
+        Ok(ValueType::Number(value as f64))
+    } else {
+        Err(RuntimeError::new(
+            "Result of bitwise operation can't be represented exactly.",
+        ))
+    }
+}
+
+/// Shifting by 64 or more would lose every bit, so it's reported instead
+fn shift_operands(a: ValueType, b: ValueType) -> Result<(i64, u32), RuntimeError> {
+    let (a, b) = integer_operands(a, b)?;
+    match b {
+        0..=63 => Ok((a, b as u32)),
+        _ => Err(RuntimeError::new("Shift amount must be between 0 and 63.")),
+    }
+}
+
+impl BitAnd for ValueType {
+    type Output = Result<Self, RuntimeError>;
+
+    fn bitand(self, rhs: Self) -> Self::Output {
+        let (a, b) = integer_operands(self, rhs)?;
+        integer_result(a & b)
+    }
+}
+
+impl BitOr for ValueType {
+    type Output = Result<Self, RuntimeError>;
+
+    fn bitor(self, rhs: Self) -> Self::Output {
+        let (a, b) = integer_operands(self, rhs)?;
+        integer_result(a | b)
+    }
+}
+
+impl BitXor for ValueType {
+    type Output = Result<Self, RuntimeError>;
+
+    fn bitxor(self, rhs: Self) -> Self::Output {
+        let (a, b) = integer_operands(self, rhs)?;
+        integer_result(a ^ b)
+    }
+}
+
+impl Shl for ValueType {
+    type Output = Result<Self, RuntimeError>;
+
+    fn shl(self, rhs: Self) -> Self::Output {
+        let (a, b) = shift_operands(self, rhs)?;
+        integer_result(a << b)
+    }
+}
+
+/// Arithmetic shift, the sign is kept
+impl Shr for ValueType {
+    type Output = Result<Self, RuntimeError>;
+
+    fn shr(self, rhs: Self) -> Self::Output {
+        let (a, b) = shift_operands(self, rhs)?;
+        integer_result(a >> b)
+    }
+}
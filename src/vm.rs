@@ -1,16 +1,49 @@
-use std::collections::HashMap;
+use std::time::Instant;
 
 use crate::{
-    chunk::Chunk, compiler::Compiler, debug::disassemble_instruction, opcode::OpCode,
-    value::ValueType, InterpretError, InterpretResult,
+    compiler::Compiler,
+    debug::disassemble_instruction,
+    heap::{Heap, HeapStats, ObjRef},
+    native,
+    object::{
+        NativeFn, ObjBoundMethod, ObjClass, ObjClosure, ObjInstance, ObjList, ObjMap, ObjNative,
+        ObjUpvalue, Object,
+    },
+    opcode::OpCode,
+    table::{StringMap, StringSet},
+    value::ValueType,
+    InterpretError, InterpretResult, RuntimeError,
 };
 
-pub struct VM {
-    chunk: Option<Chunk>,
+const FRAMES_MAX: usize = 64;
+
+pub const MAP_KEY_ERROR: &str = "Map keys must be strings, numbers, booleans or nil.";
+
+/// A single ongoing function call
+/// `slot_offset` is the index into the VM stack where the function's slot window starts
+/// `function` is the closure's function, cached since every instruction is read from it
+pub struct CallFrame {
+    closure: ObjRef,
+    function: ObjRef,
     instr_pos: usize,
+    slot_offset: usize,
+}
+
+pub struct VM {
+    frames: Vec<CallFrame>,
     debug: bool,
+    /// Print a line to stderr for every collection
+    log_gc: bool,
+    repl: bool,
     stack: Vec<ValueType>,
-    globals: HashMap<String, ValueType>,
+    globals: StringMap<ValueType>,
+    /// Names of the globals declared with `const`, they can't be assigned or defined again
+    const_globals: StringSet,
+    heap: Heap,
+    /// Interned "init", looked up on every class call
+    init_string: ObjRef,
+    /// Upvalues still pointing into the stack, sorted by stack location
+    open_upvalues: Vec<ObjRef>,
 }
 
 impl Default for VM {
@@ -21,38 +54,133 @@ impl Default for VM {
 
 impl VM {
     pub fn new() -> Self {
-        VM {
-            chunk: None,
-            instr_pos: 0,
+        let mut heap = Heap::new();
+        let init_string = heap.intern("init");
+
+        let mut vm = VM {
+            frames: Vec::with_capacity(FRAMES_MAX),
             debug: false,
+            log_gc: false,
+            repl: false,
             stack: Vec::new(),
-            globals: HashMap::new(),
+            globals: StringMap::default(),
+            const_globals: StringSet::default(),
+            heap,
+            init_string,
+            open_upvalues: Vec::new(),
+        };
+
+        vm.define_native("clock", 0, native::clock);
+        vm.define_native("len", 1, native::len);
+        vm.define_native("push", 2, native::push);
+        vm.define_native("pop", 1, native::pop);
+        vm.define_native("keys", 1, native::keys);
+        vm.define_native("values", 1, native::values);
+        vm.define_native("has", 2, native::has);
+        vm.define_native("remove", 2, native::remove);
+        vm.define_native("doc", 1, native::doc);
+        vm
+    }
+
+    /// Exposes a host function to Lox scripts as a global with the given name
+    /// Calls with a different number of arguments than `arity` are runtime errors
+    pub fn define_native(&mut self, name: &str, arity: u8, function: NativeFn) {
+        let name = self.intern(name);
+        self.push_value(ValueType::Obj(name));
+        let native = self.alloc(Object::ObjNative(ObjNative::new(name, arity, function)));
+        self.pop_value();
+
+        self.globals.insert(name, ValueType::Obj(native));
+    }
+
+    /// Strings handed to Lox must be interned, natives creating strings go through here
+    pub fn intern(&mut self, value: &str) -> ObjRef {
+        if self.heap.should_collect() {
+            self.collect_garbage();
+        }
+        self.heap.intern(value)
+    }
+
+    /// Moves the object onto the heap, collecting garbage first when the heap has grown enough
+    /// Objects referenced by the new one must already be reachable, e.g. pushed on the stack
+    pub fn alloc(&mut self, object: Object) -> ObjRef {
+        if self.heap.should_collect() {
+            self.collect_garbage();
+        }
+        self.heap.alloc(object)
+    }
+
+    /// To be called after changing an object in place, it may collect so the object
+    /// and everything it refers to must be reachable, e.g. still on the stack
+    pub fn resized(&mut self, obj: ObjRef) {
+        self.heap.resize(obj);
+        if self.heap.should_collect() {
+            self.collect_garbage();
         }
     }
 
+    /// Read access to the objects, e.g. for natives looking at their arguments
+    pub fn heap(&self) -> &Heap {
+        &self.heap
+    }
+
+    /// Write access to the objects, for natives changing their arguments in place
+    /// New objects should still be created with `alloc` so the heap gets collected
+    pub fn heap_mut(&mut self) -> &mut Heap {
+        &mut self.heap
+    }
+
+    /// Current size of the heap, live objects per kind and the collections run so far
+    pub fn heap_stats(&self) -> HeapStats {
+        self.heap.stats()
+    }
+
     pub fn set_debug(&mut self, state: bool) {
         self.debug = state
     }
 
+    pub fn set_log_gc(&mut self, state: bool) {
+        self.log_gc = state
+    }
+
+    /// In repl mode top-level expression statements print their value
+    /// and the trailing ';' of the last one may be left out
+    pub fn set_repl(&mut self, state: bool) {
+        self.repl = state
+    }
+
+    /// Collect on every allocation instead of waiting for the heap to grow,
+    /// which shakes out objects that are not reachable from the roots while still in use
+    pub fn set_stress_gc(&mut self, state: bool) {
+        self.heap.set_stress_gc(state)
+    }
+
     pub fn interpret(&mut self, source: &str) -> InterpretResult {
-        let mut chunk = Chunk::new();
-        let mut compiler = Compiler::new(source, &mut chunk);
+        let mut compiler = Compiler::new(source, &mut self.heap);
+        compiler.set_repl(self.repl);
+        let function = match compiler.compile() {
+            Ok(function) => function,
+            Err(diagnostics) => return Err(InterpretError::CompileError(diagnostics)),
+        };
 
-        if !compiler.compile() {
-            return Err(crate::InterpretError::CompileError);
-        }
+        // The top-level script sits in stack slot zero like any other callee
+        // The function stays on the stack while its closure is allocated so it is not collected
+        self.push_value(ValueType::Obj(function));
+        let closure = self.alloc(Object::ObjClosure(ObjClosure::new(function, Vec::new())));
+        self.pop_value();
+        self.push_value(ValueType::Obj(closure));
+        self.call(closure, 0)?;
 
-        self.chunk = Some(chunk);
         self.run()
     }
 
     pub fn run(&mut self) -> InterpretResult {
-        // println!("Chunk: {:?}", self.chunk.as_ref().unwrap());
         loop {
             if self.debug {
-                if let Some(chunk) = &self.chunk {
+                if let Some(frame) = self.frames.last() {
                     self.show_stack();
-                    let _ = disassemble_instruction(chunk, &self.instr_pos);
+                    let chunk = &self.heap.function(frame.function).chunk;
+                    let _ = disassemble_instruction(&self.heap, chunk, &frame.instr_pos);
                 }
             }
 
@@ -61,17 +189,50 @@ impl VM {
             match OpCode::try_from(instruction) {
                 Ok(opcode) => match opcode {
                     OpCode::RETURN => {
-                        return Ok(());
+                        // Discard the callee's slot window and leave the result in its place
+                        let result = self.pop_value().unwrap_or(ValueType::Nil);
+                        let frame = self
+                            .frames
+                            .pop()
+                            .expect("[Return] no call frame to return from");
+                        self.close_upvalues(frame.slot_offset);
+
+                        if self.frames.is_empty() {
+                            self.stack.clear();
+                            return Ok(());
+                        }
+
+                        self.stack.truncate(frame.slot_offset);
+                        self.push_value(result);
                     }
                     OpCode::CONSTANT => {
                         let constant = self.read_constant();
                         self.push_value(constant);
                     }
+                    OpCode::ConstantLong => {
+                        let constant = self.read_constant_long();
+                        self.push_value(constant);
+                    }
                     OpCode::NEGATE => self.negate_op()?,
-                    OpCode::ADD => self.binary_op(|a, b| a + b)?,
+                    OpCode::ADD => self.add_op()?,
                     OpCode::SUBTRACT => self.binary_op(|a, b| a - b)?,
                     OpCode::MULTIPLY => self.binary_op(|a, b| a * b)?,
                     OpCode::DIVIDE => self.binary_op(|a, b| a / b)?,
+                    OpCode::MODULO => self.binary_op(|a, b| a % b)?,
+                    OpCode::IntDivide => self.binary_op(ValueType::int_div)?,
+                    OpCode::POWER => self.binary_op(ValueType::pow)?,
+                    OpCode::BitAnd => self.binary_op(|a, b| a & b)?,
+                    OpCode::BitOr => self.binary_op(|a, b| a | b)?,
+                    OpCode::BitXor => self.binary_op(|a, b| a ^ b)?,
+                    OpCode::ShiftLeft => self.binary_op(|a, b| a << b)?,
+                    OpCode::ShiftRight => self.binary_op(|a, b| a >> b)?,
+                    OpCode::BitNot => match self.pop_value() {
+                        Some(value) => match value.bit_not() {
+                            Ok(result) => self.push_value(result),
+                            Err(e) => return Err(self.runtime_error(&e.message)),
+                        },
+                        None => return Err(self.runtime_error("Stack underflow.")),
+                    },
                     OpCode::NIL => self.push_value(ValueType::Nil),
                     OpCode::FALSE => self.push_value(ValueType::Bool(false)),
                     OpCode::TRUE => self.push_value(ValueType::Bool(true)),
@@ -85,123 +246,830 @@ impl VM {
                             let is_equal = a == b;
                             self.push_value(ValueType::Bool(is_equal));
                         }
-                        _ => return Err(InterpretError::RuntimeError),
+                        _ => return Err(self.runtime_error("Stack underflow.")),
                     },
                     OpCode::GREATER => self.binary_cmp(|a, b| a > b)?,
                     OpCode::LESS => self.binary_cmp(|a, b| a < b)?,
                     OpCode::PRINT => {
                         if let Some(print_value) = self.pop_value() {
-                            println!("{}", print_value);
+                            println!("{}", self.heap.value_to_string(&print_value));
                         }
                     }
                     OpCode::POP => {
                         let _ = self.pop_value();
                     }
-                    OpCode::DefineGlobal => {
-                        // Get the variable name from constant vector (chunk),
-                        // Get the value from top of the stack (value of variable)
-                        // Store in the hash table
-                        let constant_name = self.read_constant().to_string();
-                        if let Some(identifier_name) = self.peek(0) {
-                            self.globals
-                                .insert(constant_name, identifier_name.to_owned());
-                            self.pop_value();
+                    OpCode::DUP => match self.peek(0).copied() {
+                        Some(value) => self.push_value(value),
+                        None => return Err(self.runtime_error("Stack underflow.")),
+                    },
+                    OpCode::DupPair => match (self.peek(1).copied(), self.peek(0).copied()) {
+                        (Some(a), Some(b)) => {
+                            self.push_value(a);
+                            self.push_value(b);
+                        }
+                        _ => return Err(self.runtime_error("Stack underflow.")),
+                    },
+                    // Moves the top of the stack below the `depth` values under it
+                    OpCode::SINK => {
+                        let depth = self.read_byte() as usize;
+                        if depth >= self.stack.len() {
+                            return Err(self.runtime_error("Stack underflow."));
+                        }
+                        if let Some(value) = self.pop_value() {
+                            let idx = self.stack.len() - depth;
+                            self.stack.insert(idx, value);
                         }
                     }
+                    OpCode::STRINGIFY => self.stringify()?,
+                    OpCode::DefineGlobal => {
+                        let constant_name = self.read_string();
+                        self.define_global(constant_name, false)?;
+                    }
+                    OpCode::DefineGlobalLong => {
+                        let constant_name = self.read_string_long();
+                        self.define_global(constant_name, false)?;
+                    }
+                    OpCode::DefineConstGlobal => {
+                        let constant_name = self.read_string();
+                        self.define_global(constant_name, true)?;
+                    }
+                    OpCode::DefineConstGlobalLong => {
+                        let constant_name = self.read_string_long();
+                        self.define_global(constant_name, true)?;
+                    }
                     OpCode::GetGlobal => {
-                        // Get the variable name from constant vector (chunk),
-                        // Check if the key's value is present in the globals hashmap
-                        // If it does then add that to the stack
-                        // Other runtime error
-                        let constant_name = self.read_constant().to_string();
-                        if let Some(value) = self.globals.get(&constant_name) {
-                            self.push_value(value.to_owned());
-                        } else {
-                            return Err(InterpretError::RuntimeError);
-                        }
+                        let constant_name = self.read_string();
+                        self.get_global(constant_name)?;
+                    }
+                    OpCode::GetGlobalLong => {
+                        let constant_name = self.read_string_long();
+                        self.get_global(constant_name)?;
                     }
                     OpCode::SetGlobal => {
-                        let constant_name = self.read_constant().to_string();
-                        if let Some(identifier_name) = self.peek(0) {
-                            let value_to_update = identifier_name.to_owned();
-                            match self.globals.get_mut(&constant_name) {
-                                Some(value) => {
-                                    *value = value_to_update;
-                                }
-                                None => return Err(InterpretError::RuntimeError),
-                            }
-                        }
+                        let constant_name = self.read_string();
+                        self.set_global(constant_name)?;
+                    }
+                    OpCode::SetGlobalLong => {
+                        let constant_name = self.read_string_long();
+                        self.set_global(constant_name)?;
                     }
                     OpCode::GetLocal => {
-                        let slot = self.read_byte();
-                        self.push_value(self.stack[slot as usize].to_owned());
+                        let slot = self.read_byte() as usize + self.frame().slot_offset;
+                        self.push_value(self.stack[slot]);
                     }
                     OpCode::SetLocal => {
-                        let slot = self.read_byte();
+                        let slot = self.read_byte() as usize + self.frame().slot_offset;
                         if let Some(value) = self.peek(0) {
-                            self.stack[slot as usize] = value.to_owned()
+                            self.stack[slot] = *value
                         }
                     }
                     OpCode::JumpIfFalse => {
                         let offset = self.read_short();
                         if let Some(value) = self.peek(0) {
                             if value.is_falsey() {
-                                self.instr_pos += offset as usize;
+                                self.frame_mut().instr_pos += offset as usize;
                             }
                         }
                     }
                     OpCode::JUMP => {
                         let offset = self.read_short();
-                        self.instr_pos += offset as usize;
+                        self.frame_mut().instr_pos += offset as usize;
                     }
                     OpCode::LOOP => {
                         let offset = self.read_short();
-                        self.instr_pos -= offset as usize;
+                        self.frame_mut().instr_pos -= offset as usize;
+                    }
+                    OpCode::CALL => {
+                        let arg_count = self.read_byte();
+                        match self.peek(arg_count as usize) {
+                            Some(callee) => self.call_value(*callee, arg_count)?,
+                            None => return Err(self.runtime_error("Stack underflow.")),
+                        }
+                    }
+                    OpCode::CLOSURE => {
+                        let function = self.read_constant();
+                        self.make_closure(function)?;
+                    }
+                    OpCode::ClosureLong => {
+                        let function = self.read_constant_long();
+                        self.make_closure(function)?;
+                    }
+                    OpCode::GetUpvalue => {
+                        let slot = self.read_byte() as usize;
+                        let upvalue = self.heap.closure(self.frame().closure).upvalues[slot];
+                        let upvalue = self.heap.upvalue(upvalue);
+                        let value = match upvalue.closed {
+                            Some(value) => value,
+                            None => self.stack[upvalue.location],
+                        };
+                        self.push_value(value);
+                    }
+                    OpCode::SetUpvalue => {
+                        let slot = self.read_byte() as usize;
+                        let upvalue = self.heap.closure(self.frame().closure).upvalues[slot];
+                        if let Some(value) = self.peek(0).copied() {
+                            let upvalue = self.heap.upvalue_mut(upvalue);
+                            match upvalue.closed.as_mut() {
+                                Some(closed) => *closed = value,
+                                None => self.stack[upvalue.location] = value,
+                            }
+                        }
+                    }
+                    OpCode::CloseUpvalue => {
+                        self.close_upvalues(self.stack.len() - 1);
+                        self.pop_value();
+                    }
+                    OpCode::CLASS => {
+                        let class_name = self.read_string();
+                        self.define_class(class_name);
+                    }
+                    OpCode::ClassLong => {
+                        let class_name = self.read_string_long();
+                        self.define_class(class_name);
+                    }
+                    OpCode::ClassDoc => {
+                        let doc = self.read_string();
+                        self.set_class_doc(doc);
+                    }
+                    OpCode::ClassDocLong => {
+                        let doc = self.read_string_long();
+                        self.set_class_doc(doc);
+                    }
+                    OpCode::METHOD => {
+                        let method_name = self.read_string();
+                        self.define_method(method_name)?;
+                    }
+                    OpCode::MethodLong => {
+                        let method_name = self.read_string_long();
+                        self.define_method(method_name)?;
+                    }
+                    OpCode::INHERIT => {
+                        // Copy-down inheritance, the subclass starts with all of the superclass
+                        // methods and its own methods defined afterwards override them
+                        let methods = match self.peek_obj(1).map(|obj| self.heap.get(obj)) {
+                            Some(Object::ObjClass(superclass)) => superclass.methods.clone(),
+                            _ => return Err(self.runtime_error("Superclass must be a class.")),
+                        };
+                        match self.peek_obj(0) {
+                            Some(subclass) => {
+                                self.heap.class_mut(subclass).methods.extend(methods);
+                                self.resized(subclass);
+                            }
+                            None => return Err(self.runtime_error("Stack underflow.")),
+                        }
+                        self.pop_value();
+                    }
+                    OpCode::GetSuper => {
+                        let method_name = self.read_string();
+                        self.get_super(method_name)?;
+                    }
+                    OpCode::GetSuperLong => {
+                        let method_name = self.read_string_long();
+                        self.get_super(method_name)?;
+                    }
+                    OpCode::BuildList => {
+                        // The items stay on the stack until the list holding them exists
+                        let item_count = self.read_byte() as usize;
+                        let first_item = self.stack.len() - item_count;
+                        let items = self.stack[first_item..].to_vec();
+                        let list = self.alloc(Object::ObjList(ObjList::new(items)));
+
+                        self.stack.truncate(first_item);
+                        self.push_value(ValueType::Obj(list));
+                    }
+                    OpCode::BuildMap => {
+                        // Keys and values alternate on the stack and stay there until the map exists
+                        let entry_count = self.read_byte() as usize;
+                        let first_entry = self.stack.len() - entry_count * 2;
+
+                        let mut map = ObjMap::new();
+                        for entry in self.stack[first_entry..].chunks(2) {
+                            if !self.heap.is_map_key(&entry[0]) {
+                                return Err(self.runtime_error(MAP_KEY_ERROR));
+                            }
+                            map.insert(entry[0], entry[1]);
+                        }
+                        let map = self.alloc(Object::ObjMap(map));
+
+                        self.stack.truncate(first_entry);
+                        self.push_value(ValueType::Obj(map));
+                    }
+                    OpCode::GetIndex => {
+                        let (target, index) = match (self.peek(1).copied(), self.peek(0).copied()) {
+                            (Some(target), Some(index)) => (target, index),
+                            _ => return Err(self.runtime_error("Stack underflow.")),
+                        };
+                        let value = self.get_index(target, index)?;
+
+                        self.pop_value();
+                        self.pop_value();
+                        self.push_value(value);
+                    }
+                    OpCode::SetIndex => {
+                        let (target, index, value) = match (
+                            self.peek(2).copied(),
+                            self.peek(1).copied(),
+                            self.peek(0).copied(),
+                        ) {
+                            (Some(target), Some(index), Some(value)) => (target, index, value),
+                            _ => return Err(self.runtime_error("Stack underflow.")),
+                        };
+                        self.set_index(target, index, value)?;
+
+                        // Leave the assigned value on the stack in place of the target
+                        self.stack.truncate(self.stack.len() - 3);
+                        self.push_value(value);
+                    }
+                    OpCode::GetProperty => {
+                        let property_name = self.read_string();
+                        self.get_property(property_name)?;
+                    }
+                    OpCode::GetPropertyLong => {
+                        let property_name = self.read_string_long();
+                        self.get_property(property_name)?;
+                    }
+                    OpCode::SetProperty => {
+                        let property_name = self.read_string();
+                        self.set_property(property_name)?;
+                    }
+                    OpCode::SetPropertyLong => {
+                        let property_name = self.read_string_long();
+                        self.set_property(property_name)?;
                     }
                 },
-                Err(e) => Err(e)?,
+                Err(e) => return Err(self.runtime_error(&e.message)),
+            }
+        }
+    }
+
+    /// Wraps the function in a new closure and captures the upvalues listed after the instruction
+    fn make_closure(&mut self, function: ValueType) -> InterpretResult {
+        let function = match function {
+            ValueType::Obj(function) => function,
+            _ => return Err(self.runtime_error("Closure expects a function.")),
+        };
+        let upvalue_count = match self.heap.get(function) {
+            Object::ObjFunction(function) => function.upvalue_count,
+            _ => return Err(self.runtime_error("Closure expects a function.")),
+        };
+
+        // The closure goes on the stack first so it survives the upvalue allocations
+        let closure = ObjClosure::new(function, Vec::with_capacity(upvalue_count));
+        let closure = self.alloc(Object::ObjClosure(closure));
+        self.push_value(ValueType::Obj(closure));
+
+        for _ in 0..upvalue_count {
+            let is_local = self.read_byte();
+            let index = self.read_byte() as usize;
+
+            let upvalue = match is_local {
+                1 => self.capture_upvalue(self.frame().slot_offset + index),
+                _ => self.heap.closure(self.frame().closure).upvalues[index],
+            };
+            self.heap.closure_mut(closure).upvalues.push(upvalue);
+        }
+        Ok(())
+    }
+
+    fn define_class(&mut self, class_name: ObjRef) {
+        let class = self.alloc(Object::ObjClass(ObjClass::new(class_name)));
+        self.push_value(ValueType::Obj(class));
+    }
+
+    fn set_class_doc(&mut self, doc: ObjRef) {
+        if let Some(class) = self.peek_obj(0) {
+            self.heap.class_mut(class).doc = Some(doc);
+        }
+    }
+
+    /// The method closure is on top of the stack with its class right below it
+    fn define_method(&mut self, method_name: ObjRef) -> InterpretResult {
+        match (self.peek_obj(0), self.peek_obj(1)) {
+            (Some(method), Some(class)) if matches!(self.heap.get(class), Object::ObjClass(_)) => {
+                self.heap
+                    .class_mut(class)
+                    .methods
+                    .insert(method_name, method);
+                self.resized(class);
+            }
+            _ => return Err(self.runtime_error("Methods must belong to a class.")),
+        }
+        self.pop_value();
+        Ok(())
+    }
+
+    fn get_super(&mut self, method_name: ObjRef) -> InterpretResult {
+        match self.pop_value() {
+            Some(ValueType::Obj(superclass))
+                if matches!(self.heap.get(superclass), Object::ObjClass(_)) =>
+            {
+                self.bind_method(superclass, method_name)
+            }
+            _ => Err(self.runtime_error("Superclass must be a class.")),
+        }
+    }
+
+    /// Fields shadow methods, so look at the instance first
+    fn get_property(&mut self, property_name: ObjRef) -> InterpretResult {
+        let instance = match self.peek_obj(0) {
+            Some(instance) if matches!(self.heap.get(instance), Object::ObjInstance(_)) => instance,
+            _ => return Err(self.runtime_error("Only instances have properties.")),
+        };
+
+        let instance = self.heap.instance(instance);
+        let (field, class) = (instance.fields.get(&property_name).copied(), instance.class);
+        match field {
+            Some(value) => {
+                self.pop_value();
+                self.push_value(value);
+                Ok(())
+            }
+            None => self.bind_method(class, property_name),
+        }
+    }
+
+    /// Leaves the assigned value on the stack in place of the instance
+    fn set_property(&mut self, property_name: ObjRef) -> InterpretResult {
+        let instance = match self.peek_obj(1) {
+            Some(instance) if matches!(self.heap.get(instance), Object::ObjInstance(_)) => instance,
+            _ => return Err(self.runtime_error("Only instances have fields.")),
+        };
+
+        if let Some(value) = self.pop_value() {
+            self.heap
+                .instance_mut(instance)
+                .fields
+                .insert(property_name, value);
+            self.resized(instance);
+            self.pop_value();
+            self.push_value(value);
+        }
+        Ok(())
+    }
+
+    /// Get the variable name from constant vector (chunk),
+    /// Get the value from top of the stack (value of variable)
+    /// Store in the hash table
+    /// A global can be defined again, unless it was declared with `const`
+    fn define_global(&mut self, constant_name: ObjRef, is_const: bool) -> InterpretResult {
+        if self.const_globals.contains(&constant_name) {
+            let message = format!(
+                "Can't redefine constant '{}'.",
+                self.heap.as_str(constant_name)
+            );
+            return Err(self.runtime_error(&message));
+        }
+        if is_const {
+            self.const_globals.insert(constant_name);
+        }
+
+        if let Some(identifier_name) = self.peek(0) {
+            self.globals.insert(constant_name, *identifier_name);
+            self.pop_value();
+        }
+        Ok(())
+    }
+
+    /// Check if the key's value is present in the globals hashmap
+    /// If it does then add that to the stack
+    /// Other runtime error
+    fn get_global(&mut self, constant_name: ObjRef) -> InterpretResult {
+        if let Some(value) = self.globals.get(&constant_name) {
+            self.push_value(*value);
+            return Ok(());
+        }
+
+        let message = format!("Undefined variable '{}'.", self.heap.as_str(constant_name));
+        Err(self.runtime_error(&message))
+    }
+
+    fn set_global(&mut self, constant_name: ObjRef) -> InterpretResult {
+        if self.const_globals.contains(&constant_name) {
+            let message = format!(
+                "Can't assign to constant '{}'.",
+                self.heap.as_str(constant_name)
+            );
+            return Err(self.runtime_error(&message));
+        }
+
+        if let Some(identifier_name) = self.peek(0) {
+            let value_to_update = *identifier_name;
+            match self.globals.get_mut(&constant_name) {
+                Some(value) => {
+                    *value = value_to_update;
+                }
+                None => {
+                    let message =
+                        format!("Undefined variable '{}'.", self.heap.as_str(constant_name));
+                    return Err(self.runtime_error(&message));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn call_value(&mut self, callee: ValueType, arg_count: u8) -> InterpretResult {
+        let callee = match callee {
+            ValueType::Obj(callee) => callee,
+            _ => return Err(self.runtime_error("Can only call functions and classes.")),
+        };
+        let callee_slot = self.stack.len() - arg_count as usize - 1;
+
+        match self.heap.get(callee) {
+            Object::ObjClosure(_) => self.call(callee, arg_count),
+            Object::ObjClass(_) => {
+                // The new instance replaces the class in the callee slot so `init` sees it as `this`
+                // The class stays in that slot until the instance exists, which keeps it alive
+                let instance = self.alloc(Object::ObjInstance(ObjInstance::new(callee)));
+                self.stack[callee_slot] = ValueType::Obj(instance);
+
+                let initializer = self
+                    .heap
+                    .class(callee)
+                    .methods
+                    .get(&self.init_string)
+                    .copied();
+                match initializer {
+                    Some(initializer) => self.call(initializer, arg_count),
+                    None if arg_count != 0 => {
+                        let message = format!("Expected 0 arguments but got {}.", arg_count);
+                        Err(self.runtime_error(&message))
+                    }
+                    None => Ok(()),
+                }
+            }
+            Object::ObjBoundMethod(bound) => {
+                let method = bound.method;
+                self.stack[callee_slot] = bound.receiver;
+                self.call(method, arg_count)
+            }
+            Object::ObjNative(native) => {
+                let (arity, function) = (native.arity, native.function);
+                if arg_count != arity {
+                    let message = format!("Expected {} arguments but got {}.", arity, arg_count);
+                    return Err(self.runtime_error(&message));
+                }
+
+                // Natives run straight away, no frame, the callee and arguments are replaced by the result
+                // The arguments stay on the stack during the call so natives can allocate safely
+                let args = self.stack[callee_slot + 1..].to_vec();
+                let result = match function(self, &args) {
+                    Ok(result) => result,
+                    Err(e) => return Err(self.runtime_error(&e.message)),
+                };
+
+                self.stack.truncate(callee_slot);
+                self.push_value(result);
+                Ok(())
             }
+            _ => Err(self.runtime_error("Can only call functions and classes.")),
         }
     }
 
+    /// Replaces the instance on top of the stack with its method bound to it
+    fn bind_method(&mut self, class: ObjRef, name: ObjRef) -> InterpretResult {
+        let method = match self.heap.class(class).methods.get(&name) {
+            Some(method) => *method,
+            None => {
+                let message = format!("Undefined property '{}'.", self.heap.as_str(name));
+                return Err(self.runtime_error(&message));
+            }
+        };
+
+        // The receiver is only popped once the bound method exists, so it is not collected
+        if let Some(receiver) = self.peek(0).copied() {
+            let bound = self.alloc(Object::ObjBoundMethod(ObjBoundMethod::new(
+                receiver, method,
+            )));
+            self.pop_value();
+            self.push_value(ValueType::Obj(bound));
+        }
+
+        Ok(())
+    }
+
+    /// Pushes a new frame whose slot window starts at the callee, below its arguments
+    fn call(&mut self, closure: ObjRef, arg_count: u8) -> InterpretResult {
+        let function = self.heap.closure(closure).function;
+        let arity = self.heap.function(function).arity;
+        if arg_count != arity {
+            let message = format!("Expected {} arguments but got {}.", arity, arg_count);
+            return Err(self.runtime_error(&message));
+        }
+
+        if self.frames.len() == FRAMES_MAX {
+            return Err(self.runtime_error("Stack overflow."));
+        }
+
+        let slot_offset = self.stack.len() - arg_count as usize - 1;
+        self.frames.push(CallFrame {
+            closure,
+            function,
+            instr_pos: 0,
+            slot_offset,
+        });
+
+        Ok(())
+    }
+
+    /// `target[index]`, lists take a position and maps a key that must already be present
+    fn get_index(
+        &mut self,
+        target: ValueType,
+        index: ValueType,
+    ) -> Result<ValueType, InterpretError> {
+        let target = self.index_target(target)?;
+        match self.heap.get(target) {
+            Object::ObjList(_) => {
+                let position = self.list_index(target, index)?;
+                Ok(self.heap.list(target).items[position])
+            }
+            _ => {
+                self.map_key(&index)?;
+                match self.heap.map(target).get(&index) {
+                    Some(value) => Ok(value),
+                    None => {
+                        let message =
+                            format!("Undefined key '{}'.", self.heap.value_to_string(&index));
+                        Err(self.runtime_error(&message))
+                    }
+                }
+            }
+        }
+    }
+
+    /// `target[index] = value`, lists can only replace existing positions while maps add new keys
+    fn set_index(
+        &mut self,
+        target: ValueType,
+        index: ValueType,
+        value: ValueType,
+    ) -> InterpretResult {
+        let target = self.index_target(target)?;
+        match self.heap.get(target) {
+            Object::ObjList(_) => {
+                let position = self.list_index(target, index)?;
+                self.heap.list_mut(target).items[position] = value;
+            }
+            _ => {
+                self.map_key(&index)?;
+                self.heap.map_mut(target).insert(index, value);
+                self.resized(target);
+            }
+        }
+        Ok(())
+    }
+
+    /// Only lists and maps can be indexed
+    fn index_target(&mut self, target: ValueType) -> Result<ObjRef, InterpretError> {
+        match target {
+            ValueType::Obj(obj)
+                if matches!(self.heap.get(obj), Object::ObjList(_) | Object::ObjMap(_)) =>
+            {
+                Ok(obj)
+            }
+            _ => Err(self.runtime_error("Only lists and maps can be indexed.")),
+        }
+    }
+
+    fn map_key(&mut self, key: &ValueType) -> InterpretResult {
+        match self.heap.is_map_key(key) {
+            true => Ok(()),
+            false => Err(self.runtime_error(MAP_KEY_ERROR)),
+        }
+    }
+
+    /// Checks that the index is a whole number within the bounds of the list
+    fn list_index(&mut self, list: ObjRef, index: ValueType) -> Result<usize, InterpretError> {
+        let index = match index {
+            ValueType::Number(index) => index,
+            _ => return Err(self.runtime_error("List index must be a number.")),
+        };
+        if index.fract() != 0.0 {
+            return Err(self.runtime_error("List index must be a whole number."));
+        }
+
+        let len = self.heap.list(list).items.len();
+        if index < 0.0 || index >= len as f64 {
+            let message = format!(
+                "List index {} is out of range for a list of length {}.",
+                index, len
+            );
+            return Err(self.runtime_error(&message));
+        }
+
+        Ok(index as usize)
+    }
+
+    /// Closures capturing the same variable must share the upvalue,
+    /// so reuse an open one for that stack location if it exists
+    fn capture_upvalue(&mut self, location: usize) -> ObjRef {
+        let insert_at = self
+            .open_upvalues
+            .partition_point(|upvalue| self.heap.upvalue(*upvalue).location < location);
+
+        if let Some(upvalue) = self.open_upvalues.get(insert_at) {
+            if self.heap.upvalue(*upvalue).location == location {
+                return *upvalue;
+            }
+        }
+
+        let upvalue = self.alloc(Object::ObjUpvalue(ObjUpvalue::new(location)));
+        self.open_upvalues.insert(insert_at, upvalue);
+        upvalue
+    }
+
+    /// Moves every open upvalue at or above `last` off the stack and into the upvalue itself
+    fn close_upvalues(&mut self, last: usize) {
+        let close_from = self
+            .open_upvalues
+            .partition_point(|upvalue| self.heap.upvalue(*upvalue).location < last);
+
+        for upvalue in self.open_upvalues.drain(close_from..) {
+            let upvalue = self.heap.upvalue_mut(upvalue);
+            upvalue.closed = Some(self.stack[upvalue.location]);
+        }
+    }
+
+    /// Marks everything reachable from the roots and frees the rest
+    /// Strings only referenced by the intern table are dropped from it before the sweep
+    fn collect_garbage(&mut self) {
+        let started = Instant::now();
+        let before = self.heap.bytes_allocated();
+        if self.log_gc {
+            eprintln!("-- gc begin");
+        }
+
+        self.mark_roots();
+        self.heap.trace_references();
+        self.heap.remove_white_strings();
+        self.heap.sweep();
+
+        let pause = started.elapsed();
+        self.heap.record_collection(pause);
+        if self.log_gc {
+            let after = self.heap.bytes_allocated();
+            eprintln!(
+                "-- gc end: collected {} bytes (from {} to {}) next at {}, took {:?}",
+                before - after,
+                before,
+                after,
+                self.heap.next_gc(),
+                pause
+            );
+        }
+    }
+
+    /// The roots are the values the VM can reach directly, without going through another object
+    fn mark_roots(&mut self) {
+        for value in self.stack.iter() {
+            self.heap.mark_value(value);
+        }
+
+        for frame in self.frames.iter() {
+            self.heap.mark_object(frame.closure);
+        }
+
+        for upvalue in self.open_upvalues.iter() {
+            self.heap.mark_object(*upvalue);
+        }
+
+        for (name, value) in self.globals.iter() {
+            self.heap.mark_object(*name);
+            self.heap.mark_value(value);
+        }
+
+        self.heap.mark_object(self.init_string);
+    }
+
+    fn frame(&self) -> &CallFrame {
+        self.frames.last().expect("[Frame] no call frame in vm!")
+    }
+
+    fn frame_mut(&mut self) -> &mut CallFrame {
+        self.frames
+            .last_mut()
+            .expect("[Frame] no call frame in vm!")
+    }
+
     /// In the OpCode vector, Constant takes two position, constant opcode and idx of the constant
     /// Get the constant index as we are already at the instruction position where index is stored
     /// Get the constant using that index position
     fn read_constant(&mut self) -> ValueType {
         let constant_idx = self.read_byte();
-        if let Some(ref mut chunk) = self.chunk {
-            return chunk.get_constant(constant_idx as usize);
+        self.heap
+            .function(self.frame().function)
+            .chunk
+            .get_constant(constant_idx as usize)
+    }
+
+    /// Reads a constant that the compiler guarantees to be a string, like variable and property names
+    fn read_string(&mut self) -> ObjRef {
+        match self.read_constant() {
+            ValueType::Obj(name) => name,
+            _ => unreachable!("[Read String] constant is not a string!"),
+        }
+    }
+
+    fn read_string_long(&mut self) -> ObjRef {
+        match self.read_constant_long() {
+            ValueType::Obj(name) => name,
+            _ => unreachable!("[Read String] constant is not a string!"),
         }
+    }
 
-        panic!("[Read Constant] no chunk in vm to read!");
+    /// Long constants store the index in the next three bytes, high byte first
+    fn read_constant_long(&mut self) -> ValueType {
+        let constant_idx = ((self.read_byte() as usize) << 16)
+            | ((self.read_byte() as usize) << 8)
+            | (self.read_byte() as usize);
+        self.heap
+            .function(self.frame().function)
+            .chunk
+            .get_constant(constant_idx)
     }
 
-    /// Gets the current instruction position from self
+    /// Gets the current instruction position from the current frame
     /// Gets the OpCode as u8 using the current instruction position
     fn read_byte(&mut self) -> u8 {
-        if let Some(chunk) = &self.chunk {
-            let curr_instr_pos = self.instr_pos;
-            self.instr_pos += 1;
+        let frame = self
+            .frames
+            .last_mut()
+            .expect("[Frame] no call frame in vm!");
+        let curr_instr_pos = frame.instr_pos;
+        frame.instr_pos += 1;
 
-            return chunk.op_codes_at(curr_instr_pos);
-        }
-        unreachable!("[Read Byte] no chunk in vm to read!");
+        self.heap
+            .function(frame.function)
+            .chunk
+            .op_codes_at(curr_instr_pos)
     }
 
     fn read_short(&mut self) -> u16 {
-        assert!(self.chunk.is_some());
-        self.instr_pos += 2;
-        let chunk = self.chunk.as_mut().unwrap();
+        let frame = self
+            .frames
+            .last_mut()
+            .expect("[Frame] no call frame in vm!");
+        frame.instr_pos += 2;
 
-        let left_byte = (chunk.op_codes_at(self.instr_pos - 2) as u16) << 8;
-        let right_byte = chunk.op_codes_at(self.instr_pos - 1) as u16;
+        let chunk = &self.heap.function(frame.function).chunk;
+        let left_byte = (chunk.op_codes_at(frame.instr_pos - 2) as u16) << 8;
+        let right_byte = chunk.op_codes_at(frame.instr_pos - 1) as u16;
 
         left_byte | right_byte
     }
 
+    /// `+` concatenates strings, the result is interned like every other string
+    fn add_op(&mut self) -> InterpretResult {
+        if let (Some(b), Some(a)) = (self.peek_obj(0), self.peek_obj(1)) {
+            if let (Object::ObjString(a), Object::ObjString(b)) =
+                (self.heap.get(a), self.heap.get(b))
+            {
+                let mut new_str = String::with_capacity(a.as_str().len() + b.as_str().len());
+                new_str.push_str(a.as_str());
+                new_str.push_str(b.as_str());
+
+                // Both operands stay on the stack until the result is allocated
+                if self.heap.should_collect() {
+                    self.collect_garbage();
+                }
+                let result = self.heap.intern_owned(new_str);
+                self.pop_value();
+                self.pop_value();
+                self.push_value(ValueType::Obj(result));
+                return Ok(());
+            }
+        }
+
+        self.binary_op(|a, b| a + b)
+    }
+
+    /// Replaces the value on top of the stack with its printed form, strings are left as they are
+    fn stringify(&mut self) -> InterpretResult {
+        let value = match self.peek(0).copied() {
+            Some(value) => value,
+            None => return Err(self.runtime_error("Stack underflow.")),
+        };
+        if let ValueType::Obj(obj) = value {
+            if let Object::ObjString(_) = self.heap.get(obj) {
+                return Ok(());
+            }
+        }
+
+        let text = self.heap.value_to_string(&value);
+        // The value stays on the stack until the string is allocated
+        if self.heap.should_collect() {
+            self.collect_garbage();
+        }
+        let result = self.heap.intern_owned(text);
+        self.pop_value();
+        self.push_value(ValueType::Obj(result));
+        Ok(())
+    }
+
     fn binary_op<F>(&mut self, op: F) -> InterpretResult
     where
-        F: Fn(ValueType, ValueType) -> Result<ValueType, InterpretError>,
+        F: Fn(ValueType, ValueType) -> Result<ValueType, RuntimeError>,
     {
         if let (Some(v_b), Some(v_a)) = (self.pop_value(), self.pop_value()) {
             match op(v_a, v_b) {
@@ -209,10 +1077,10 @@ impl VM {
                     self.push_value(v);
                     return Ok(());
                 }
-                Err(e) => Err(e)?,
+                Err(e) => return Err(self.runtime_error(&e.message)),
             }
         }
-        Err(InterpretError::RuntimeError)
+        Err(self.runtime_error("Stack underflow."))
     }
 
     fn binary_cmp<F>(&mut self, op: F) -> InterpretResult
@@ -220,11 +1088,15 @@ impl VM {
         F: Fn(ValueType, ValueType) -> bool,
     {
         if let (Some(v_b), Some(v_a)) = (self.pop_value(), self.pop_value()) {
+            if !matches!((&v_a, &v_b), (ValueType::Number(_), ValueType::Number(_))) {
+                return Err(self.runtime_error("Operands must be numbers."));
+            }
+
             let cmp_result = op(v_a, v_b);
             self.push_value(ValueType::Bool(cmp_result));
             return Ok(());
         }
-        Err(InterpretError::RuntimeError)
+        Err(self.runtime_error("Stack underflow."))
     }
 
     fn negate_op(&mut self) -> InterpretResult {
@@ -234,10 +1106,52 @@ impl VM {
                     *num = -*num;
                     return Ok(());
                 }
-                _ => return Err(InterpretError::RuntimeError),
+                _ => return Err(self.runtime_error("Operand must be a number.")),
             }
         }
-        Err(InterpretError::RuntimeError)
+        Err(self.runtime_error("Stack underflow."))
+    }
+
+    /// Builds the error for the instruction currently executing, with a trace of every active call
+    /// The stack is reset afterwards, so the VM can be reused for another `interpret`
+    fn runtime_error(&mut self, message: &str) -> InterpretError {
+        let trace: Vec<String> = self
+            .frames
+            .iter()
+            .rev()
+            .map(|frame| {
+                let function = self.heap.function(frame.function);
+                let line = function
+                    .chunk
+                    .line_from_offset(frame.instr_pos.saturating_sub(1));
+                match function.name {
+                    Some(fn_name) => format!("[line {}] in {}()", line, self.heap.as_str(fn_name)),
+                    None => format!("[line {}] in script", line),
+                }
+            })
+            .collect();
+
+        let line = self.frames.last().map_or(0, |frame| {
+            self.heap
+                .function(frame.function)
+                .chunk
+                .line_from_offset(frame.instr_pos.saturating_sub(1))
+        });
+
+        self.reset_stack();
+        InterpretError::RuntimeError(RuntimeError {
+            message: message.to_string(),
+            line,
+            trace,
+        })
+    }
+
+    /// Closures that escaped keep working, their open upvalues are closed before the stack goes away
+    fn reset_stack(&mut self) {
+        self.close_upvalues(0);
+        self.stack.clear();
+        self.frames.clear();
+        self.open_upvalues.clear();
     }
 
     fn peek(&self, distance: usize) -> Option<&ValueType> {
@@ -248,6 +1162,14 @@ impl VM {
         self.stack.get(stack_len - 1 - distance)
     }
 
+    /// Like `peek` but only for objects, giving back the handle
+    fn peek_obj(&self, distance: usize) -> Option<ObjRef> {
+        match self.peek(distance) {
+            Some(ValueType::Obj(obj)) => Some(*obj),
+            _ => None,
+        }
+    }
+
     /// Strore the ValueType in the Vm's stack
     fn push_value(&mut self, value: ValueType) {
         self.stack.push(value);
@@ -263,7 +1185,7 @@ impl VM {
         }
         print!("          ");
         for stack_value in self.stack.iter() {
-            print!("[ {} ]", stack_value);
+            print!("[ {} ]", self.heap.value_to_string(stack_value));
         }
         println!();
     }